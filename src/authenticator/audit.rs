@@ -0,0 +1,149 @@
+//! # 审计日志
+//! 记录验证器导出、导入凭证，生成密钥和策略拒绝请求的事件，保存在密钥库中。
+//! 每条记录包含前一条记录的摘要，修改、删除或重排中间的记录都会使之后的摘要校验失败
+use crate::authenticator::consent::key_fingerprint;
+use crate::authenticator::error::AuthenticatorError as AuthError;
+use crate::authenticator::policy::Suite;
+use crate::authenticator::protocol::hpke_format::HPKEParameters;
+use crate::authenticator::unix_time;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditEvent {
+    /// 作为导出方处理导出请求
+    Export,
+    /// 作为导入方处理导出响应
+    Import,
+    /// 生成接收密钥
+    KeyGenerated,
+    /// 生成Auth模式下认证发送方的身份密钥
+    IdentityKeyGenerated,
+    /// 导出策略拒绝了请求
+    PolicyRefused,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditOutcome {
+    Success,
+    Failure(String),
+}
+
+/// 一次事件的内容
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    pub timestamp: u64,
+    pub event: AuditEvent,
+    /// 对方的公钥指纹，没有公钥时为psk_id
+    pub peer: Option<String>,
+    pub rp_ids: Vec<String>,
+    pub suite: Option<Suite>,
+    /// 生成密钥的KEM
+    pub kem: Option<u16>,
+    pub outcome: AuditOutcome,
+}
+
+impl AuditRecord {
+    pub fn new(event: AuditEvent) -> Self {
+        Self {
+            timestamp: unix_time(),
+            event,
+            peer: None,
+            rp_ids: Vec::new(),
+            suite: None,
+            kem: None,
+            outcome: AuditOutcome::Success,
+        }
+    }
+
+    pub fn key_generated(kem: u16) -> Self {
+        Self {
+            kem: Some(kem),
+            ..Self::new(AuditEvent::KeyGenerated)
+        }
+    }
+
+    /// 记录协商出的套件和对方身份
+    pub fn negotiated(&mut self, params: &HPKEParameters) {
+        self.suite = Some(Suite::from(params));
+        self.peer = peer(params);
+    }
+
+    /// 按处理结果填写事件结果
+    pub fn finish<R>(mut self, result: &Result<R, AuthError>) -> Self {
+        if let Err(e) = result {
+            self.outcome = AuditOutcome::Failure(e.to_string());
+        }
+        self
+    }
+}
+
+/// 参数中公钥的指纹，与导出策略中的导入方指纹相同；没有公钥时使用psk_id
+fn peer(params: &HPKEParameters) -> Option<String> {
+    match params.decode_jwk().ok().and_then(|jwk| jwk.pk) {
+        Some(pk) => Some(key_fingerprint(&pk)),
+        None => params.psk_id.clone(),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub seq: u64,
+    /// 前一条记录的摘要，第一条记录为空
+    pub prev: String,
+    #[serde(flatten)]
+    pub record: AuditRecord,
+    /// 覆盖除`hash`以外所有字段的SHA-256摘要，Base64url编码
+    pub hash: String,
+}
+
+impl AuditEntry {
+    fn digest(&self) -> Result<String, AuthError> {
+        let mut entry = self.clone();
+        entry.hash.clear();
+        Ok(BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(serde_json::to_vec(&entry)?)))
+    }
+}
+
+/// 只能追加的审计日志
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct AuditLog(Vec<AuditEntry>);
+
+impl AuditLog {
+    pub fn entries(&self) -> &[AuditEntry] {
+        &self.0
+    }
+
+    pub fn append(&mut self, record: AuditRecord) -> Result<(), AuthError> {
+        let (seq, prev) = match self.0.last() {
+            Some(last) => (last.seq + 1, last.hash.clone()),
+            None => (0, String::new()),
+        };
+        let mut entry = AuditEntry {
+            seq,
+            prev,
+            record,
+            hash: String::new(),
+        };
+        entry.hash = entry.digest()?;
+        self.0.push(entry);
+        Ok(())
+    }
+
+    /// 依次校验每条记录的序号、前一条记录的摘要和自身的摘要，返回第一条不一致的记录序号
+    pub fn verify(&self) -> Result<(), AuthError> {
+        let mut prev = "";
+        for (seq, entry) in self.0.iter().enumerate() {
+            if entry.seq != seq as u64 || entry.prev != prev || entry.hash != entry.digest()? {
+                return Err(AuthError::AuditChainBroken(seq as u64));
+            }
+            prev = &entry.hash;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+//! # 用户同意
+//! 导出方在加密凭证前询问用户，展示导入方、请求的RP和凭证类型以及将要导出的凭证，
+//! 用户可以同意、拒绝或只导出其中一部分
+use crate::authenticator::error::AuthenticatorError as AuthError;
+use crate::authenticator::protocol::cxf::{CredentialType, Item};
+use crate::authenticator::protocol::hpke_format::HPKEParameters;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+/// 导入方的身份：请求中选定套件的公钥指纹，使用PSK时还包括配对得到的psk_id
+#[derive(Debug, Clone)]
+pub struct ImporterIdentity {
+    pub key_fingerprint: String,
+    pub psk_id: Option<String>,
+}
+
+impl ImporterIdentity {
+    /// 由请求中选定的套件得到导入方身份，指纹为公钥SHA-256的前16字节
+    pub fn of(params: &HPKEParameters) -> Result<Self, AuthError> {
+        let pk = params.decode_jwk()?.pk.unwrap_or_default();
+        Ok(Self {
+            key_fingerprint: key_fingerprint(&pk),
+            psk_id: params.psk_id.clone(),
+        })
+    }
+
+    /// 身份标识与公钥指纹或psk_id相同
+    pub fn matches(&self, id: &str) -> bool {
+        self.key_fingerprint == id || self.psk_id.as_deref() == Some(id)
+    }
+}
+
+/// 公钥SHA-256的前16字节，Base64url编码
+pub fn key_fingerprint(pk: &[u8]) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(&Sha256::digest(pk)[..16])
+}
+
+/// 交给用户确认的导出请求
+#[derive(Debug)]
+pub struct ConsentRequest<'a> {
+    pub importer: &'a ImporterIdentity,
+    pub rp_id: &'a str,
+    /// 导入方接受的凭证类型，为`None`时接受所有类型
+    pub credential_types: Option<&'a [CredentialType]>,
+    /// 满足请求的候选条目
+    pub candidates: &'a [Item],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Consent {
+    Approve,
+    Deny,
+    /// 只导出这些id对应的条目
+    Subset(Vec<String>),
+}
+
+pub trait ConsentProvider {
+    fn consent(&self, request: &ConsentRequest) -> Consent;
+}
+
+/// 不询问用户，同意所有请求
+pub struct AutoApprove;
+
+impl ConsentProvider for AutoApprove {
+    fn consent(&self, _request: &ConsentRequest) -> Consent {
+        Consent::Approve
+    }
+}
@@ -1,4 +1,9 @@
-#![allow(dead_code)]
+#![allow(
+    dead_code,
+    clippy::collapsible_match,
+    clippy::extra_unused_type_parameters,
+    clippy::type_complexity
+)]
 //! Here's the gist of this file: Instead of doing things at the type level, you can use zero-sized
 //! types and runtime validity checks to do all of HPKE. This file is a rough idea of how one would
 //! go about implementing that. There isn't too much repetition. The main part where you have to
@@ -13,7 +18,9 @@
 //! of runtime checks.
 
 use hpke::{
-    aead::{Aead, AeadCtxR, AeadCtxS, AeadTag, AesGcm128, AesGcm256, ChaCha20Poly1305},
+    aead::{
+        Aead, AeadCtxR, AeadCtxS, AeadTag, AesGcm128, AesGcm256, ChaCha20Poly1305, ExportOnlyAead,
+    },
     kdf::{HkdfSha256, HkdfSha384, HkdfSha512, Kdf as KdfTrait},
     kem::{
         DhP256HkdfSha256, DhP384HkdfSha384, DhP521HkdfSha512, Kem as KemTrait, X25519HkdfSha256,
@@ -23,7 +30,6 @@ use hpke::{
 };
 
 use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
-use crate::authenticator::crypto::psk;
 
 pub trait AgileAeadCtxS {
     fn seal_in_place_detached(
@@ -32,6 +38,13 @@ pub trait AgileAeadCtxS {
         aad: &[u8],
     ) -> Result<AgileAeadTag, AgileHpkeError>;
     fn seal(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError>;
+    /// RFC 9180中的密钥导出接口，双方以相同的上下文得到相同的导出密钥
+    fn export(&self, exporter_ctx: &[u8], out_buf: &mut [u8]) -> Result<(), AgileHpkeError>;
+    fn export_secret(&self, exporter_ctx: &[u8], len: usize) -> Result<Vec<u8>, AgileHpkeError> {
+        let mut out = vec![0u8; len];
+        self.export(exporter_ctx, &mut out)?;
+        Ok(out)
+    }
 }
 
 pub trait AgileAeadCtxR {
@@ -42,6 +55,12 @@ pub trait AgileAeadCtxR {
         tag_bytes: &[u8],
     ) -> Result<(), AgileHpkeError>;
     fn open(&mut self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError>;
+    fn export(&self, exporter_ctx: &[u8], out_buf: &mut [u8]) -> Result<(), AgileHpkeError>;
+    fn export_secret(&self, exporter_ctx: &[u8], len: usize) -> Result<Vec<u8>, AgileHpkeError> {
+        let mut out = vec![0u8; len];
+        self.export(exporter_ctx, &mut out)?;
+        Ok(out)
+    }
 }
 
 type AgileAeadTag = Vec<u8>;
@@ -56,6 +75,8 @@ pub enum AgileHpkeError {
     UnknownAlgIdent(&'static str, u16),
     /// Represents an error in the `hpke` crate
     HpkeError(HpkeError),
+    /// 仅导出（AEAD 0xFFFF）的上下文不能加密或解密，`hpke`在这种情况下会panic
+    ExportOnly,
 }
 
 // This just wraps the HpkeError
@@ -77,6 +98,7 @@ impl From<AgileHpkeError> for String {
             AgileHpkeError::HpkeError(s) => {
                 format!("HPKE错误：{:?}", s)
             }
+            AgileHpkeError::ExportOnly => "仅导出模式的上下文不能加密或解密".to_string(),
         }
     }
 }
@@ -87,13 +109,18 @@ impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AgileAeadCtxS for AeadCtxS<A, Kdf, K
         plaintext: &mut [u8],
         aad: &[u8],
     ) -> Result<Vec<u8>, AgileHpkeError> {
+        check_not_export_only::<A>()?;
         self.seal_in_place_detached(plaintext, aad)
             .map(|tag| tag.to_bytes().to_vec())
             .map_err(Into::into)
     }
     fn seal(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
+        check_not_export_only::<A>()?;
         self.seal(plaintext, aad).map_err(Into::into)
     }
+    fn export(&self, exporter_ctx: &[u8], out_buf: &mut [u8]) -> Result<(), AgileHpkeError> {
+        self.export(exporter_ctx, out_buf).map_err(Into::into)
+    }
 }
 
 impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AgileAeadCtxR for AeadCtxR<A, Kdf, Kem> {
@@ -103,13 +130,25 @@ impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AgileAeadCtxR for AeadCtxR<A, Kdf, K
         aad: &[u8],
         tag_bytes: &[u8],
     ) -> Result<(), AgileHpkeError> {
+        check_not_export_only::<A>()?;
         let tag = AeadTag::<A>::from_bytes(tag_bytes)?;
         self.open_in_place_detached(ciphertext, aad, &tag)
             .map_err(Into::into)
     }
     fn open(&mut self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
+        check_not_export_only::<A>()?;
         self.open(ciphertext, aad).map_err(Into::into)
     }
+    fn export(&self, exporter_ctx: &[u8], out_buf: &mut [u8]) -> Result<(), AgileHpkeError> {
+        self.export(exporter_ctx, out_buf).map_err(Into::into)
+    }
+}
+
+fn check_not_export_only<A: Aead>() -> Result<(), AgileHpkeError> {
+    if A::AEAD_ID == ExportOnlyAead::AEAD_ID {
+        return Err(AgileHpkeError::ExportOnly);
+    }
+    Ok(())
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -117,6 +156,7 @@ pub(crate) enum AeadAlg {
     AesGcm128,
     AesGcm256,
     ChaCha20Poly1305,
+    ExportOnlyAead,
 }
 
 impl AeadAlg {
@@ -125,6 +165,7 @@ impl AeadAlg {
             AeadAlg::AesGcm128 => "AesGcm128",
             AeadAlg::AesGcm256 => "AesGcm256",
             AeadAlg::ChaCha20Poly1305 => "ChaCha20Poly1305",
+            AeadAlg::ExportOnlyAead => "ExportOnlyAead",
         }
     }
 
@@ -133,6 +174,7 @@ impl AeadAlg {
             0x01 => AeadAlg::AesGcm128,
             0x02 => AeadAlg::AesGcm256,
             0x03 => AeadAlg::ChaCha20Poly1305,
+            0xFFFF => AeadAlg::ExportOnlyAead,
             _ => return Err(AgileHpkeError::UnknownAlgIdent("AeadAlg", id)),
         };
 
@@ -144,6 +186,7 @@ impl AeadAlg {
             AeadAlg::AesGcm128 => 0x01,
             AeadAlg::AesGcm256 => 0x02,
             AeadAlg::ChaCha20Poly1305 => 0x03,
+            AeadAlg::ExportOnlyAead => 0xFFFF,
         }
     }
 }
@@ -235,6 +278,17 @@ impl KemAlg {
         }
     }
 
+    /// 编码后公钥的字节长度，NIST曲线为未压缩点格式
+    pub fn get_pubkey_len(&self) -> usize {
+        match self {
+            KemAlg::X25519HkdfSha256 => 32,
+            KemAlg::X448HkdfSha512 => 56,
+            KemAlg::DhP256HkdfSha256 => 65,
+            KemAlg::DhP384HkdfSha384 => 97,
+            KemAlg::DhP521HkdfSha512 => 133,
+        }
+    }
+
     fn kdf_alg(&self) -> KdfAlg {
         match self {
             KemAlg::X25519HkdfSha256 => KdfAlg::HkdfSha256,
@@ -593,7 +647,7 @@ pub fn agile_setup_sender<R: CryptoRng + RngCore>(
     #[rustfmt::skip]
     hpke_dispatch!(
         res, to_match,
-        (ChaCha20Poly1305, AesGcm128, AesGcm256),
+        (ChaCha20Poly1305, AesGcm128, AesGcm256, ExportOnlyAead),
         (HkdfSha256, HkdfSha384, HkdfSha512),
         (X25519HkdfSha256, DhP256HkdfSha256, DhP384HkdfSha384, DhP521HkdfSha512),
         R,
@@ -676,7 +730,7 @@ pub fn agile_setup_receiver(
     #[rustfmt::skip]
     hpke_dispatch!(
         res, to_match,
-        (ChaCha20Poly1305, AesGcm128, AesGcm256),
+        (ChaCha20Poly1305, AesGcm128, AesGcm256, ExportOnlyAead),
         (HkdfSha256, HkdfSha384, HkdfSha512),
         (X25519HkdfSha256, DhP256HkdfSha256, DhP384HkdfSha384, DhP521HkdfSha512),
         Unit,
@@ -702,6 +756,7 @@ fn align_main() {
         AeadAlg::AesGcm128,
         AeadAlg::AesGcm256,
         AeadAlg::ChaCha20Poly1305,
+        AeadAlg::ExportOnlyAead,
     ];
     let supported_kem_algs = &[
         KemAlg::X25519HkdfSha256,
@@ -768,9 +823,27 @@ fn align_main() {
                 )
                 .unwrap();
 
+                // 双方导出的密钥一致
+                let exported = aead_ctx1.export_secret(b"exporter context", 32).unwrap();
+                assert_eq!(
+                    exported,
+                    aead_ctx2.export_secret(b"exporter context", 32).unwrap()
+                );
+
                 // Test an encryption-decryption round trip
                 let msg = b"paper boy paper boy";
                 let aad = b"all about that paper, boy";
+                if aead_alg == AeadAlg::ExportOnlyAead {
+                    assert!(matches!(
+                        aead_ctx1.seal(msg, aad),
+                        Err(AgileHpkeError::ExportOnly)
+                    ));
+                    assert!(matches!(
+                        aead_ctx2.open(msg, aad),
+                        Err(AgileHpkeError::ExportOnly)
+                    ));
+                    continue;
+                }
                 let ciphertext = aead_ctx1.seal(msg, aad).unwrap();
                 let roundtrip_plaintext = aead_ctx2.open(&ciphertext, aad).unwrap();
 
@@ -1,33 +1,97 @@
+//! # HPKE加密
+//! 分块加密导出数据，并从HPKE上下文导出短认证码和回执密钥
 #[allow(unused)]
 pub(crate) mod agility;
 
 use crate::authenticator::protocol::hpke_format::HPKEMode;
 use agility::*;
+pub use agility::{AgileAeadCtxR, AgileAeadCtxS};
 use hpke::aead::Aead;
 use hpke::kdf::Kdf;
 use hpke::{Kem, OpModeR, OpModeS, PskBundle, Serializable};
 use rand::{rngs::StdRng, SeedableRng};
+use sha2::{Digest, Sha256};
 
-const INFO: &[u8] = b"information";
-const AAD: &[u8] = b"information aad";
+/// 绑定到HPKE上下文的info和AAD，由请求和响应的内容计算，使密文只对引出它的请求有效
+pub struct Binding<'a> {
+    pub info: &'a [u8],
+    pub aad: &'a [u8],
+}
+
+/// 预共享密钥及其标识，psk_id在HPKE参数中协商
+pub struct Psk<'a> {
+    pub id: &'a [u8],
+    pub key: &'a [u8],
+}
+
+/// 从HPKE上下文导出短认证码所用的上下文和长度
+pub const SAS_CONTEXT: &[u8] = b"fido-cx short authentication string";
+pub const SAS_SECRET_LEN: usize = 16;
+/// RFC 9180规定的仅导出AEAD标识，该套件只能用于导出密钥
+pub const EXPORT_ONLY_AEAD: u16 = 0xFFFF;
 
-//协议中没有规定psk_id应该在哪里进行协商，这里只用一个进行处理
-static PSK_ID: &[u8; 40] = b"preshared key attempt #5, take 2. action";
-static PSK_BYTES: [u8; 512] = [0; 512];
-pub fn psk(kdf_alg: KdfAlg) -> AgilePskBundle<'static> {
-    AgilePskBundle(PskBundle {
-        psk: &PSK_BYTES[..kdf_alg.get_digest_len()],
-        psk_id: PSK_ID,
-    })
+/// 由HPKE导出密钥和导入方揭示的随机数得到的6位短认证码，双方显示的认证码一致说明没有被中间人替换公钥
+pub fn sas_code(secret: &[u8], reveal: &[u8]) -> String {
+    let digest = Sha256::new()
+        .chain_update(secret)
+        .chain_update(reveal)
+        .finalize();
+    let value = digest
+        .iter()
+        .take(8)
+        .fold(0u64, |acc, b| acc << 8 | u64::from(*b));
+    format!("{:06}", value % 1_000_000)
 }
 
+/// 分块加密时每条记录的默认明文长度，归档仍整体在内存中加解密
+pub const RECORD_SIZE: usize = 64 * 1024;
+
+/// 加密结果：密文记录、封装密钥和可继续导出密钥的发送方上下文
+pub type Sealed = (Vec<Vec<u8>>, Vec<u8>, Box<dyn AgileAeadCtxS>);
+/// 解密结果：明文和接收方上下文
+pub type Opened = (Vec<u8>, Box<dyn AgileAeadCtxR>);
+
+/// 从发送方上下文导出密钥，接收方以相同的上下文调用[`export_secret_r`]得到相同的密钥
+pub fn export_secret_s(
+    ctx: &dyn AgileAeadCtxS,
+    exporter_ctx: &[u8],
+    len: usize,
+) -> Result<Vec<u8>, String> {
+    Ok(ctx.export_secret(exporter_ctx, len)?)
+}
+
+pub fn export_secret_r(
+    ctx: &dyn AgileAeadCtxR,
+    exporter_ctx: &[u8],
+    len: usize,
+) -> Result<Vec<u8>, String> {
+    Ok(ctx.export_secret(exporter_ctx, len)?)
+}
+
+fn psk_bundle<'a>(psk: Option<&Psk<'a>>) -> Result<AgilePskBundle<'a>, String> {
+    let psk = psk.ok_or("PSK模式缺少预共享密钥")?;
+    Ok(AgilePskBundle(PskBundle {
+        psk: psk.key,
+        psk_id: psk.id,
+    }))
+}
+
+/// 生成KEM的密钥对，X448尚未实现，返回错误
 pub fn gen_key_pair(kem: u16) -> Result<(Vec<u8>, Vec<u8>), String> {
     let mut csprng = StdRng::from_entropy();
     let kem_alg = KemAlg::try_from_u16(kem)?;
+    if kem_alg == KemAlg::X448HkdfSha512 {
+        return Err("X448的密钥生成尚未实现".to_string());
+    }
     let pair = agile_gen_keypair(kem_alg, &mut csprng);
     Ok((pair.0.privkey_bytes, pair.1.pubkey_bytes))
 }
 
+/// KEM公钥的字节长度
+pub fn public_key_len(kem: u16) -> Result<usize, String> {
+    Ok(KemAlg::try_from_u16(kem)?.get_pubkey_len())
+}
+
 fn trans_keypair(keypair: &(Vec<u8>, Vec<u8>), kem_alg: KemAlg) -> AgileKeypair {
     AgileKeypair(
         AgilePrivateKey {
@@ -41,26 +105,32 @@ fn trans_keypair(keypair: &(Vec<u8>, Vec<u8>), kem_alg: KemAlg) -> AgileKeypair
     )
 }
 
-///加密数据
+///加密数据，返回密文记录、封装密钥和HPKE上下文，上下文可继续用于导出密钥
+/// 数据按`record_size`切分为多条记录，在同一个上下文中依次加密，序号保证记录不能被重排
+/// AEAD为仅导出时不能加密，`data`应为空，此时不产生记录
 /// Flag为RFC 9180 Algorithm Identifiers规定的标志的16位形式
+#[allow(clippy::too_many_arguments)]
 pub fn encrypt(
     kem_flag: u16,
     kdf_flag: u16,
     aead_flag: u16,
     data: &[u8],
+    record_size: usize,
     pki: &[u8],
     mode: &HPKEMode,
     key_pair: &(Vec<u8>, Vec<u8>),
-) -> Result<(Vec<u8>, Vec<u8>), String> {
+    psk: Option<&Psk>,
+    binding: &Binding,
+) -> Result<Sealed, String> {
     let mut csprng = StdRng::from_entropy();
     let (aead_alg, kdf_alg, kem_alg) = match_algorithm(kem_flag, kdf_flag, aead_flag)?;
 
     let op_mode_ty = match mode {
         HPKEMode::Base => AgileOpModeSTy::Base,
-        HPKEMode::Psk => AgileOpModeSTy::Psk(psk(kdf_alg)),
+        HPKEMode::Psk => AgileOpModeSTy::Psk(psk_bundle(psk)?),
         HPKEMode::Auth => AgileOpModeSTy::Auth(trans_keypair(key_pair, kem_alg)),
         HPKEMode::AuthPsk => {
-            AgileOpModeSTy::AuthPsk(trans_keypair(key_pair, kem_alg), psk(kdf_alg))
+            AgileOpModeSTy::AuthPsk(trans_keypair(key_pair, kem_alg), psk_bundle(psk)?)
         }
     };
 
@@ -78,41 +148,60 @@ pub fn encrypt(
         kem_alg,
         &op_mode_s,
         &pki,
-        INFO,
+        binding.info,
         &mut csprng,
     )?;
 
-    let ciphertext = aead_ctx1.seal(data, AAD)?;
-    Ok((ciphertext, encapped_key.encapped_key_bytes))
+    if record_size == 0 {
+        return Err("记录长度不能为0".to_string());
+    }
+    let records = match aead_alg {
+        AeadAlg::ExportOnlyAead if data.is_empty() => Vec::new(),
+        // 空数据也需要一条最终记录，否则无法区分截断
+        _ if data.is_empty() => vec![aead_ctx1.seal(data, &record_aad(binding.aad, true))?],
+        _ => {
+            let count = data.len().div_ceil(record_size);
+            data.chunks(record_size)
+                .enumerate()
+                .map(|(i, chunk)| aead_ctx1.seal(chunk, &record_aad(binding.aad, i + 1 == count)))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    Ok((records, encapped_key.encapped_key_bytes, aead_ctx1))
 }
 
-/// 解密数据
+/// 按顺序解密各条记录，返回拼接后的明文和HPKE上下文，上下文可继续用于导出密钥
+/// 所有记录解密成功后才返回明文，不会先交出未经最终记录确认的部分数据
+/// 记录被重排时序号不匹配，被截断时最后一条记录不是最终记录，均会解密失败
 /// Flag为RFC 9180 Algorithm Identifiers规定的标志的16位形式
+#[allow(clippy::too_many_arguments)]
 pub fn decrypt(
     kem_flag: u16,
     kdf_flag: u16,
     aead_flag: u16,
-    ciphertext: &[u8],
+    records: &[Vec<u8>],
     ski: &[u8],
     pki: &[u8],
     encapsulated_key: &[u8],
     mode: &HPKEMode,
     pke: &Option<Vec<u8>>,
-) -> Result<Vec<u8>, String> {
+    psk: Option<&Psk>,
+    binding: &Binding,
+) -> Result<Opened, String> {
     let (aead_alg, kdf_alg, kem_alg) = match_algorithm(kem_flag, kdf_flag, aead_flag)?;
     let op_mode_ty = match mode {
         HPKEMode::Base => AgileOpModeRTy::Base,
-        HPKEMode::Psk => AgileOpModeRTy::Psk(psk(kdf_alg)),
+        HPKEMode::Psk => AgileOpModeRTy::Psk(psk_bundle(psk)?),
         HPKEMode::Auth => AgileOpModeRTy::Auth(AgilePublicKey {
             kem_alg,
-            pubkey_bytes: pke.to_owned().unwrap(),
+            pubkey_bytes: sender_key(pke)?,
         }),
         HPKEMode::AuthPsk => AgileOpModeRTy::AuthPsk(
             AgilePublicKey {
                 kem_alg,
-                pubkey_bytes: pke.to_owned().unwrap(),
+                pubkey_bytes: sender_key(pke)?,
             },
-            psk(kdf_alg),
+            psk_bundle(psk)?,
         ),
     };
     let op_mode_r = AgileOpModeR {
@@ -140,9 +229,27 @@ pub fn decrypt(
         &op_mode_r,
         &recip_keypair,
         &encapped_key,
-        INFO,
+        binding.info,
     )?;
-    Ok(aead_ctx2.open(&ciphertext, AAD)?)
+    if records.is_empty() && aead_alg != AeadAlg::ExportOnlyAead {
+        return Err("缺少密文记录".to_string());
+    }
+    let mut plaintext = Vec::new();
+    for (i, record) in records.iter().enumerate() {
+        let last = i + 1 == records.len();
+        plaintext.extend(aead_ctx2.open(record, &record_aad(binding.aad, last))?);
+    }
+    Ok((plaintext, aead_ctx2))
+}
+
+/// Auth模式下认证发送方所用的公钥
+fn sender_key(pke: &Option<Vec<u8>>) -> Result<Vec<u8>, String> {
+    pke.clone().ok_or("缺少发送方公钥".to_string())
+}
+
+/// 每条记录的AAD在公共AAD后附加是否为最终记录的标志
+fn record_aad(aad: &[u8], last: bool) -> Vec<u8> {
+    [aad, &[u8::from(last)]].concat()
 }
 
 fn match_algorithm(kem: u16, kdf: u16, aead: u16) -> Result<(AeadAlg, KdfAlg, KemAlg), String> {
@@ -155,17 +262,19 @@ fn match_algorithm(kem: u16, kdf: u16, aead: u16) -> Result<(AeadAlg, KdfAlg, Ke
 pub fn _encrypt_str<AeadTrait: Aead, KdfTrait: Kdf, KemTrait: Kem>(
     data: &[u8],
     pki: &KemTrait::PublicKey,
+    binding: &Binding,
 ) -> Result<(Vec<u8>, Vec<u8>), String> {
     let mut csprng = StdRng::from_entropy();
-    let (encapsulated_key, mut encryption_context) = hpke::setup_sender::<
-        AeadTrait,
-        KdfTrait,
-        KemTrait,
-        _,
-    >(&OpModeS::Base, pki, INFO, &mut csprng)
-    .map_err(|e| e.to_string())?;
+    let (encapsulated_key, mut encryption_context) =
+        hpke::setup_sender::<AeadTrait, KdfTrait, KemTrait, _>(
+            &OpModeS::Base,
+            pki,
+            binding.info,
+            &mut csprng,
+        )
+        .map_err(|e| e.to_string())?;
     let ciphertext = encryption_context
-        .seal(data, AAD)
+        .seal(data, binding.aad)
         .expect("encryption failed!");
     Ok((ciphertext, encapsulated_key.to_bytes().to_vec()))
 }
@@ -174,17 +283,18 @@ pub fn _decrypt_str<AeadTrait: Aead, KdfTrait: Kdf, KemTrait: Kem>(
     ciphertext: &[u8],
     ski: &KemTrait::PrivateKey,
     encapsulated_key: &KemTrait::EncappedKey,
+    binding: &Binding,
 ) -> Result<Vec<u8>, String> {
     let mut decryption_context = hpke::setup_receiver::<AeadTrait, KdfTrait, KemTrait>(
         &OpModeR::Base,
         ski,
         encapsulated_key,
-        INFO,
+        binding.info,
     )
     .expect("failed to set up receiver!");
 
     let plaintext = decryption_context
-        .open(ciphertext, AAD)
+        .open(ciphertext, binding.aad)
         .expect("invalid ciphertext!");
     Ok(plaintext)
 }
@@ -1,9 +1,11 @@
 use crate::authenticator::error::AuthenticatorError::{CodeError, InternalError};
+use crate::authenticator::protocol::export_error::ErrorCode;
 use base64::DecodeError;
 use serde_json;
 use std::fmt::{Display, Formatter};
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum AuthenticatorError {
     InnerAuthenticatorError(String),
     RequestNotAllowed(String),
@@ -12,6 +14,25 @@ pub enum AuthenticatorError {
     CryptoError(String),
     CodeError(String),
     CredentialNotFound,
+    IncorrectPin,
+    UnsupportedVersion(u16),
+    InvalidRequest(String),
+    InvalidPublicKey(u16),
+    UnknownRequest,
+    RequestExpired,
+    RequestConsumed,
+    UnknownPsk(String),
+    PairingFailed(String),
+    SasNotConfirmed,
+    InvalidReceipt(String),
+    /// 导入方揭示的随机数与请求中的承诺不一致
+    InvalidSasReveal,
+    /// 导出方返回的错误消息
+    ExportFailed(ErrorCode, String),
+    /// 审计日志中第一条校验失败的记录序号
+    AuditChainBroken(u64),
+    /// 没有该KEM的密钥对，且无法为其生成
+    KeyNotFound(u16),
 }
 
 impl Display for AuthenticatorError {
@@ -41,6 +62,51 @@ impl Display for AuthenticatorError {
                 AuthenticatorError::CredentialNotFound => {
                     "没有找到相应的凭证".to_string()
                 }
+                AuthenticatorError::IncorrectPin => {
+                    "PIN错误，无法解锁密钥库".to_string()
+                }
+                AuthenticatorError::UnsupportedVersion(v) => {
+                    format!("不支持的协议版本：{}", v)
+                }
+                AuthenticatorError::InvalidRequest(e) => {
+                    format!("请求格式错误：{}", e)
+                }
+                AuthenticatorError::InvalidPublicKey(kem) => {
+                    format!("KEM {:#06x}的公钥缺失或长度错误", kem)
+                }
+                AuthenticatorError::UnknownRequest => {
+                    "没有与响应对应的请求".to_string()
+                }
+                AuthenticatorError::RequestExpired => {
+                    "请求已过期".to_string()
+                }
+                AuthenticatorError::RequestConsumed => {
+                    "请求已处理过响应".to_string()
+                }
+                AuthenticatorError::UnknownPsk(id) => {
+                    format!("未知的预共享密钥：{}", id)
+                }
+                AuthenticatorError::PairingFailed(e) => {
+                    format!("配对失败：{}", e)
+                }
+                AuthenticatorError::SasNotConfirmed => {
+                    "短认证码未经确认，拒绝导入".to_string()
+                }
+                AuthenticatorError::InvalidReceipt(e) => {
+                    format!("导入回执无效：{}", e)
+                }
+                AuthenticatorError::InvalidSasReveal => {
+                    "揭示的随机数与短认证码承诺不一致".to_string()
+                }
+                AuthenticatorError::ExportFailed(code, e) => {
+                    format!("导出方拒绝了请求（{:?}）：{}", code, e)
+                }
+                AuthenticatorError::AuditChainBroken(seq) => {
+                    format!("审计日志从第{}条记录起被篡改", seq)
+                }
+                AuthenticatorError::KeyNotFound(kem) => {
+                    format!("没有KEM {:#06x}的密钥对", kem)
+                }
             }
         )
     }
@@ -0,0 +1,58 @@
+//! # 扩展
+//! 导入方在请求的`known_extensions`中列出自身注册的扩展，导出方只应用其中自己也注册了的扩展，
+//! 并在响应中列出实际应用的扩展，导入方据此进行后处理
+//!
+//! 未注册的扩展名及其数据会被忽略，扩展数据均为任意JSON，不会导致请求或响应解析失败
+use crate::authenticator::error::AuthenticatorError as AuthError;
+use crate::authenticator::protocol::cxf::{Header, Item};
+use itertools::Itertools;
+use serde_json::Value;
+
+pub trait Extension {
+    /// 扩展名称，出现在`known_extensions`中
+    fn name(&self) -> &str;
+    /// 导入方随请求发送的扩展数据
+    fn request_data(&self) -> Option<Value> {
+        None
+    }
+    /// 导出方在加密前转换将要导出的条目，`data`为请求中该扩展附带的数据
+    fn on_export(&self, _items: &mut Vec<Item>, _data: Option<&Value>) -> Result<(), AuthError> {
+        Ok(())
+    }
+    /// 导入方对解密后的数据进行后处理
+    fn on_import(&self, _header: &mut Header) -> Result<(), AuthError> {
+        Ok(())
+    }
+}
+
+/// 验证器注册的扩展
+#[derive(Default)]
+pub struct Extensions(Vec<Box<dyn Extension>>);
+
+impl Extensions {
+    /// 注册扩展，同名扩展会被替换
+    pub fn register(&mut self, extension: Box<dyn Extension>) {
+        self.0.retain(|ext| ext.name() != extension.name());
+        self.0.push(extension);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Extension> {
+        self.0
+            .iter()
+            .find(|ext| ext.name() == name)
+            .map(|ext| ext.as_ref())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.0.iter().map(|ext| ext.name().to_string()).collect()
+    }
+
+    /// 已注册且在`names`中出现的扩展，保持`names`中的顺序
+    pub fn negotiate<'a>(&'a self, names: &'a [String]) -> impl Iterator<Item = &'a dyn Extension> {
+        names.iter().unique().filter_map(|name| self.get(name))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
@@ -1,11 +1,47 @@
+use crate::authenticator::audit::{AuditLog, AuditRecord};
 use crate::authenticator::error::AuthenticatorError as AuthError;
+use crate::authenticator::keystore::{ExportRecord, KeyEntry, KeyPair, PendingRequest};
 use crate::authenticator::protocol::{
-    archive::ArchiveAlgorithm, credential::Credential, hpke_format::HPKEParameters,
+    archive::ArchiveAlgorithm, credential::Credential, cxf::CredentialType,
+    hpke_format::HPKEParameters, request::ExportRequest,
 };
 
 pub trait InnerAuthenticator {
     fn support_algorithms(&self) -> (Vec<HPKEParameters>, Vec<ArchiveAlgorithm>);
+    /// 作为导入方时接受的凭证类型，为空或包含所有类型时请求中不再列出
+    fn credential_types(&self) -> Vec<CredentialType>;
     fn get_credentials(&self) -> Result<Vec<impl Credential>, AuthError>;
     fn store_credential(&self, credential: impl Credential) -> Result<(), AuthError>;
-    fn key_pair(&self, kem: u16) -> (Vec<u8>, Vec<u8>);
+    /// 查找KEM对应的接收密钥(私钥, 公钥)，用于解密响应，没有该KEM的密钥时返回[`AuthError::KeyNotFound`]
+    fn key_pair(&self, kem: u16) -> Result<KeyPair, AuthError>;
+    /// 查找KEM对应的长期身份密钥，Auth模式下用于认证发送方，与接收密钥相互独立
+    fn identity_key_pair(&self, kem: u16) -> Result<KeyPair, AuthError>;
+    /// 记录已发出的导出请求和为其生成的临时接收密钥，处理响应时据此重新计算info和AAD并解密
+    fn save_pending(&self, request: &ExportRequest, keys: Vec<KeyEntry>) -> Result<(), AuthError>;
+    /// 按nonce查找已发出的导出请求
+    fn pending_request(&self, nonce: &str) -> Result<Option<PendingRequest>, AuthError>;
+    /// 已保存的预共享密钥标识
+    fn psk_ids(&self) -> Vec<String>;
+    /// 按psk_id查找预共享密钥
+    fn psk(&self, psk_id: &str) -> Result<Option<Vec<u8>>, AuthError>;
+    /// 保存预共享密钥，同一psk_id的密钥会被替换
+    fn add_psk(&self, psk_id: &str, psk: &[u8]) -> Result<(), AuthError>;
+    /// 将请求标记为已处理，并销毁其临时密钥
+    fn consume_pending(&self, nonce: &str) -> Result<(), AuthError>;
+    /// 请求过期时销毁其临时密钥，保留请求记录
+    fn discard_pending_keys(&self, nonce: &str) -> Result<(), AuthError>;
+    /// 作为导出方保存导出记录，等待导入回执
+    fn save_export(&self, nonce: &str, record: ExportRecord) -> Result<(), AuthError>;
+    /// 按请求的nonce查找导出记录
+    fn export_record(&self, nonce: &str) -> Result<Option<ExportRecord>, AuthError>;
+    /// 回执处理完成后删除导出记录
+    fn remove_export(&self, nonce: &str) -> Result<(), AuthError>;
+    /// 删除id对应的一个已存储凭证
+    fn delete_credential(&self, id: &str) -> Result<(), AuthError>;
+    /// 将凭证标记为已迁移
+    fn mark_migrated(&self, id: &str) -> Result<(), AuthError>;
+    fn is_migrated(&self, id: &str) -> bool;
+    /// 在审计日志末尾追加一条记录
+    fn append_audit(&self, record: AuditRecord) -> Result<(), AuthError>;
+    fn audit_log(&self) -> AuditLog;
 }
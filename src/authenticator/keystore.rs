@@ -0,0 +1,396 @@
+//! # 密钥库
+//! 将验证器的HPKE密钥对持久化到磁盘，使一次会话中构造的导出请求在程序重启后仍然可以解密对应的响应
+//! 已发出但尚未收到响应的导出请求也保存在密钥库中
+//!
+//! 密钥库文件有两种格式：明文格式直接保存[`KeyStore`]；加密格式使用由PIN经Argon2id派生的密钥，
+//! 以ChaCha20-Poly1305对整个密钥库进行封装，KDF参数和盐值以明文保存在文件头中并作为AAD参与认证
+use crate::authenticator::audit::{AuditEvent, AuditLog, AuditRecord};
+use crate::authenticator::crypto::gen_key_pair;
+use crate::authenticator::error::AuthenticatorError as AuthError;
+use crate::authenticator::protocol::request::ExportRequest;
+use crate::authenticator::unix_time;
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::prelude::{BASE64_URL_SAFE, BASE64_URL_SAFE_NO_PAD};
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// 未指定路径时使用的密钥库文件
+pub const DEFAULT_KEYSTORE_PATH: &str = "keystore.json";
+
+/// 请求过期后其记录的保留时间，单位为秒
+const PENDING_RETENTION: u64 = 24 * 60 * 60;
+
+/// (私钥, 公钥)
+pub type KeyPair = (Vec<u8>, Vec<u8>);
+
+/// 密钥库中的单个密钥对，私钥和公钥均为Base64url编码
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyEntry {
+    pub key_id: String,
+    pub kem: u16,
+    pub created_at: u64,
+    pub sk: String,
+    pub pk: String,
+}
+
+impl KeyEntry {
+    pub fn generate(kem: u16) -> Result<Self, AuthError> {
+        let (sk, pk) = gen_key_pair(kem).map_err(AuthError::CryptoError)?;
+        Ok(Self {
+            key_id: key_id(&pk),
+            kem,
+            created_at: unix_time(),
+            sk: BASE64_URL_SAFE.encode(&sk),
+            pk: BASE64_URL_SAFE.encode(&pk),
+        })
+    }
+
+    pub fn key_pair(&self) -> Result<KeyPair, AuthError> {
+        Ok((
+            BASE64_URL_SAFE.decode(&self.sk)?,
+            BASE64_URL_SAFE.decode(&self.pk)?,
+        ))
+    }
+}
+
+/// 已发出的导出请求，收到响应后标记为已处理，防止同一响应被重放
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingRequest {
+    pub request: ExportRequest,
+    pub consumed_at: Option<u64>,
+    /// 为该请求生成的临时接收密钥，每个KEM一个，处理完响应或请求过期后销毁
+    #[serde(default)]
+    pub keys: Vec<KeyEntry>,
+}
+
+impl PendingRequest {
+    /// 查找KEM对应的临时密钥对
+    pub fn key_pair(&self, kem: u16) -> Result<Option<KeyPair>, AuthError> {
+        find_key(&self.keys, kem)
+    }
+}
+
+/// 导出方为每次导出保存的记录，收到导入回执时据此验证并处理已导出的凭证
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportRecord {
+    /// 从HPKE上下文导出的回执密钥，Base64url编码
+    pub receipt_key: String,
+    /// 导出的条目，以条目摘要为键
+    pub items: BTreeMap<String, ExportedItem>,
+    pub expires_at: u64,
+    /// 从HPKE上下文导出的短认证码密钥和请求中的承诺，导入方揭示随机数后据此计算短认证码
+    #[serde(default)]
+    pub sas_secret: String,
+    #[serde(default)]
+    pub sas_commitment: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportedItem {
+    /// 响应中的条目id
+    pub id: String,
+    /// 条目对应的已存储凭证的id，响应中的id为避免重复可能被改写
+    pub source: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct KeyStore {
+    pub keys: Vec<KeyEntry>,
+    /// 已发出的导出请求，以nonce为键
+    #[serde(default)]
+    pub pending: BTreeMap<String, PendingRequest>,
+    /// 预共享密钥，以psk_id为键，Base64url编码
+    #[serde(default)]
+    pub psks: BTreeMap<String, String>,
+    /// 等待导入回执的导出记录，以请求的nonce为键
+    #[serde(default)]
+    pub exports: BTreeMap<String, ExportRecord>,
+    /// 已确认迁移到其他验证器的凭证id及确认时间
+    #[serde(default)]
+    pub migrated: BTreeMap<String, u64>,
+    /// Auth模式下认证发送方的身份密钥，与`keys`中的接收密钥分开保存和轮换
+    #[serde(default)]
+    pub identities: Vec<KeyEntry>,
+    /// 导出、导入和生成密钥的审计日志
+    #[serde(default)]
+    pub audit: AuditLog,
+}
+
+impl KeyStore {
+    /// 从文件中读取密钥库，文件不存在时返回空的密钥库
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, AuthError> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), AuthError> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// 使用PIN解锁密钥库，返回密钥库及用于重新封装的密钥
+    /// 文件不存在时以该PIN创建新的密钥库；明文格式的密钥库会在下一次保存时被加密
+    pub fn unlock<P: AsRef<Path>>(path: P, pin: &str) -> Result<(Self, WrappingKey), AuthError> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Ok((Self::default(), WrappingKey::derive(pin, KdfParams::new())?))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match serde_json::from_str(&content)? {
+            KeyStoreFile::Sealed(sealed) => {
+                let key = WrappingKey::derive(pin, sealed.kdf.clone())?;
+                let plain = key.open(&sealed)?;
+                Ok((serde_json::from_slice(&plain)?, key))
+            }
+            KeyStoreFile::Plain(store) => Ok((store, WrappingKey::derive(pin, KdfParams::new())?)),
+        }
+    }
+
+    /// 使用派生密钥加密后保存密钥库
+    pub fn seal<P: AsRef<Path>>(&self, path: P, key: &WrappingKey) -> Result<(), AuthError> {
+        let sealed = key.seal(&serde_json::to_vec(self)?)?;
+        fs::write(path, serde_json::to_string_pretty(&sealed)?)?;
+        Ok(())
+    }
+
+    /// 只为密钥库中缺失的KEM生成密钥对，返回是否生成了新的密钥
+    pub fn ensure_keys(&mut self, kems: &[u16]) -> Result<bool, AuthError> {
+        let mut generated = false;
+        for kem in kems {
+            if !self.keys.iter().any(|entry| entry.kem == *kem) {
+                self.keys.push(KeyEntry::generate(*kem)?);
+                self.audit.append(AuditRecord::key_generated(*kem))?;
+                generated = true;
+            }
+        }
+        Ok(generated)
+    }
+
+    /// 记录新发出的请求及其临时密钥，并清除过期已久的记录
+    /// 过期的记录保留一段时间，使重放的响应仍能得到准确的错误，但其临时密钥立即销毁
+    pub fn add_pending(&mut self, request: ExportRequest, keys: Vec<KeyEntry>) {
+        let now = unix_time();
+        self.pending
+            .retain(|_, pending| pending.request.expires_at + PENDING_RETENTION > now);
+        self.discard_expired_keys();
+        self.pending.insert(
+            request.nonce.clone(),
+            PendingRequest {
+                request,
+                consumed_at: None,
+                keys,
+            },
+        );
+    }
+
+    /// 销毁已过期请求的临时密钥，返回是否有密钥被销毁
+    pub fn discard_expired_keys(&mut self) -> bool {
+        let now = unix_time();
+        let mut discarded = false;
+        for pending in self.pending.values_mut() {
+            if pending.request.is_expired(now) && !pending.keys.is_empty() {
+                pending.keys.clear();
+                discarded = true;
+            }
+        }
+        discarded
+    }
+
+    /// 保存导出记录，同时清理早已过期的记录
+    pub fn add_export(&mut self, nonce: &str, record: ExportRecord) {
+        let now = unix_time();
+        self.exports
+            .retain(|_, record| record.expires_at + PENDING_RETENTION > now);
+        self.exports.insert(nonce.to_string(), record);
+    }
+
+    /// 查找KEM对应的接收密钥对
+    pub fn key_pair(&self, kem: u16) -> Result<Option<KeyPair>, AuthError> {
+        find_key(&self.keys, kem)
+    }
+
+    /// 查找KEM对应的身份密钥对，缺失时生成，返回密钥对和是否新生成
+    pub fn identity_key_pair(&mut self, kem: u16) -> Result<(KeyPair, bool), AuthError> {
+        if let Some(pair) = find_key(&self.identities, kem)? {
+            return Ok((pair, false));
+        }
+        let entry = KeyEntry::generate(kem)?;
+        let pair = entry.key_pair()?;
+        self.identities.push(entry);
+        self.audit.append(AuditRecord {
+            kem: Some(kem),
+            ..AuditRecord::new(AuditEvent::IdentityKeyGenerated)
+        })?;
+        Ok((pair, true))
+    }
+
+    /// 按KEM标识组织的密钥对表，与`PinInner::keys`一致
+    pub fn key_pairs(&self) -> Result<HashMap<u16, KeyPair>, AuthError> {
+        self.keys
+            .iter()
+            .map(|entry| Ok((entry.kem, entry.key_pair()?)))
+            .collect()
+    }
+}
+
+fn find_key(entries: &[KeyEntry], kem: u16) -> Result<Option<KeyPair>, AuthError> {
+    entries
+        .iter()
+        .find(|entry| entry.kem == kem)
+        .map(KeyEntry::key_pair)
+        .transpose()
+}
+
+/// 密钥库文件及其保存格式，验证器状态变化时据此写回
+pub struct KeyStoreHandle {
+    path: PathBuf,
+    key: Option<WrappingKey>,
+}
+
+impl KeyStoreHandle {
+    pub fn plain<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            key: None,
+        }
+    }
+
+    pub fn sealed<P: AsRef<Path>>(path: P, key: WrappingKey) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            key: Some(key),
+        }
+    }
+
+    pub fn save(&self, store: &KeyStore) -> Result<(), AuthError> {
+        match &self.key {
+            Some(key) => store.seal(&self.path, key),
+            None => store.save(&self.path),
+        }
+    }
+}
+
+/// 密钥库文件头中的Argon2id参数，内存开销单位为KiB
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KdfParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    pub salt: String,
+}
+
+impl KdfParams {
+    /// 使用默认开销参数和随机盐值
+    pub fn new() -> Self {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Self {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+            salt: BASE64_URL_SAFE.encode(salt),
+        }
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 加密格式的密钥库文件
+#[derive(Serialize, Deserialize, Debug)]
+struct SealedKeyStore {
+    kdf: KdfParams,
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyStoreFile {
+    Sealed(SealedKeyStore),
+    Plain(KeyStore),
+}
+
+/// 由PIN派生的密钥库封装密钥
+pub struct WrappingKey {
+    key: [u8; 32],
+    kdf: KdfParams,
+}
+
+impl WrappingKey {
+    pub fn derive(pin: &str, kdf: KdfParams) -> Result<Self, AuthError> {
+        let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(32))
+            .map_err(|e| AuthError::CryptoError(format!("KDF参数错误: {}", e)))?;
+        let salt = BASE64_URL_SAFE.decode(&kdf.salt)?;
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(pin.as_bytes(), &salt, &mut key)
+            .map_err(|e| AuthError::CryptoError(format!("KDF错误: {}", e)))?;
+        Ok(Self { key, kdf })
+    }
+
+    fn seal(&self, plain: &[u8]) -> Result<SealedKeyStore, AuthError> {
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let aad = serde_json::to_vec(&self.kdf)?;
+        let ciphertext = self
+            .cipher()
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plain,
+                    aad: &aad,
+                },
+            )
+            .map_err(|e| AuthError::CryptoError(e.to_string()))?;
+        Ok(SealedKeyStore {
+            kdf: self.kdf.clone(),
+            nonce: BASE64_URL_SAFE.encode(nonce),
+            ciphertext: BASE64_URL_SAFE.encode(ciphertext),
+        })
+    }
+
+    /// 认证失败只可能来自错误的PIN或被篡改的文件，统一视为PIN错误
+    fn open(&self, sealed: &SealedKeyStore) -> Result<Vec<u8>, AuthError> {
+        let nonce = BASE64_URL_SAFE.decode(&sealed.nonce)?;
+        if nonce.len() != 12 {
+            return Err(AuthError::CodeError("密钥库nonce长度错误".to_string()));
+        }
+        let ciphertext = BASE64_URL_SAFE.decode(&sealed.ciphertext)?;
+        let aad = serde_json::to_vec(&sealed.kdf)?;
+        self.cipher()
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| AuthError::IncorrectPin)
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.key))
+    }
+}
+
+/// 密钥标识，取公钥SHA-256摘要的前8字节
+pub fn key_id(pk: &[u8]) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(&Sha256::digest(pk)[..8])
+}
@@ -1,96 +1,718 @@
-use crate::authenticator::crypto::{decrypt, encrypt};
+use crate::authenticator::crypto::{
+    decrypt, encrypt, export_secret_r, export_secret_s, public_key_len, sas_code, Binding, Opened,
+    Psk, Sealed, RECORD_SIZE, SAS_CONTEXT, SAS_SECRET_LEN,
+};
 use crate::authenticator::protocol::archive::ArchiveAlgorithm;
-use crate::authenticator::protocol::credential::Credential;
+use crate::authenticator::protocol::credential::{Credential, StructuredSingleFileCredential};
+use crate::authenticator::protocol::cxf::{Account, CredentialType, Header, Item};
+use crate::authenticator::protocol::export_error::ExportError;
 use crate::authenticator::protocol::hpke_format::HPKEMode::{Auth, AuthPsk};
 use crate::authenticator::protocol::hpke_format::HPKEParameters;
+use crate::authenticator::protocol::receipt::{
+    ImportReceipt, ReceiptAction, ReceiptStatus, RECEIPT_CONTEXT, RECEIPT_KEY_LEN,
+};
 use crate::authenticator::protocol::request::{ExportRequest, ResponseMode};
-use crate::authenticator::protocol::response::ExportResponse;
-use base64::prelude::BASE64_URL_SAFE;
+use crate::authenticator::protocol::response::{Backup, ExportResponse};
+use crate::authenticator::protocol::sas::{SasReveal, COMMITMENT_LEN};
+use crate::authenticator::protocol::version::{
+    check_requested, check_version, decode_request, decode_response, peek_version, PROTOCOL_VERSION,
+};
+use audit::{AuditEvent, AuditRecord};
+use base64::prelude::{BASE64_URL_SAFE, BASE64_URL_SAFE_NO_PAD};
 use base64::Engine;
+use consent::{AutoApprove, Consent, ConsentProvider, ConsentRequest, ImporterIdentity};
 use error::{AuthenticatorError as AuthError, AuthenticatorError::*};
+use extension::{Extension, Extensions};
 use inner::InnerAuthenticator;
-use protocol::credential::StructuredSingleFileCredential;
+use keystore::{ExportRecord, ExportedItem, KeyEntry, KeyPair};
+use pairing::{PairingMessage, PairingSession};
+use policy::Policy;
+use relay::{Delivery, Relay};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+pub mod audit;
+pub mod consent;
 pub mod crypto;
-mod error;
+pub mod error;
+pub mod extension;
 pub mod inner;
+pub mod keystore;
+pub mod pairing;
 pub mod pin;
+pub mod policy;
 pub mod protocol;
+pub mod relay;
+
+/// 导出时写入CXF头部的导出方信息
+const EXPORTER_RP_ID: &str = "fido-cx.local";
+const EXPORTER_DISPLAY_NAME: &str = "FIDO凭证交换协议仿真";
+
+/// 请求中各列表的长度上限
+const MAX_HPKE_PARAMETERS: usize = 16;
+const MAX_ARCHIVE_ALGORITHMS: usize = 8;
+const MAX_CREDENTIAL_TYPES: usize = 32;
+const MAX_EXTENSIONS: usize = 32;
+/// 请求nonce解码后的字节长度范围
+const MIN_NONCE_LEN: usize = 16;
+const MAX_NONCE_LEN: usize = 64;
+const MAX_PSK_ID_LEN: usize = 64;
+/// 接受的最长请求有效期和签发时间的最大时钟偏差，单位为秒
+const MAX_REQUEST_LIFETIME: u64 = 24 * 60 * 60;
+const MAX_CLOCK_SKEW: u64 = 5 * 60;
+/// 由临时私钥派生短认证码随机数的域分隔标签
+const REVEAL_LABEL: &[u8] = b"fido-cx sas reveal";
+
+/// 解析后的导出响应，用户确认短认证码后调用[`Authenticator::commit_import`]才会存储到内部验证器
+#[derive(Debug)]
+pub struct ImportResult {
+    pub header: Header,
+    /// 导出方因导入方不接受而跳过的凭证类型
+    pub skipped_types: Vec<CredentialType>,
+    /// 由HPKE上下文和导入方承诺的随机数得到的6位短认证码，应与导出方显示的一致
+    pub sas: String,
+    confirmed: bool,
+    /// 请求中承诺的随机数，通过[`Authenticator::sas_reveal`]揭示给导出方
+    reveal: Vec<u8>,
+    /// 生成导入回执所需的请求nonce、回执密钥和收到的条目摘要
+    nonce: String,
+    receipt_key: Vec<u8>,
+    item_hashes: Vec<String>,
+}
+
+impl ImportResult {
+    /// 用户确认双方显示的短认证码一致
+    pub fn confirm(&mut self) {
+        self.confirmed = true;
+    }
+}
 
 /// 验证器实体，不包括Fido Client部分
 pub struct Authenticator<T: InnerAuthenticator> {
     pub inner: T,
+    pub extensions: Extensions,
+    /// 导出时每条密文记录的明文长度
+    pub record_size: usize,
+    /// 导出方处理请求时遵循的策略，默认不做限制
+    pub policy: Policy,
+    /// 导出前询问用户，默认同意所有请求
+    consent: Box<dyn ConsentProvider>,
 }
 
 impl<T: InnerAuthenticator> Authenticator<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            extensions: Extensions::default(),
+            record_size: RECORD_SIZE,
+            policy: Policy::default(),
+            consent: Box::new(AutoApprove),
+        }
+    }
+
+    pub fn set_consent_provider(&mut self, consent: impl ConsentProvider + 'static) {
+        self.consent = Box::new(consent);
+    }
+
+    pub fn register_extension(&mut self, extension: impl Extension + 'static) {
+        self.extensions.register(Box::new(extension));
+    }
+
+    /// 请求中的公钥为本次请求生成的临时密钥，私钥只保存在已发出请求的记录中
     pub fn construct_export_request(&self, rp_id: String) -> Result<String, AuthError> {
+        let mut request = self.build_request(rp_id, ResponseMode::Direct);
+        let keys = ephemeral_keys(&mut request.hpke_parameters)?;
+        request.sas_commitment = Some(SasReveal::commitment(&reveal_nonce(&keys)?));
+        self.inner.save_pending(&request, keys)?;
+        serde_json::to_string_pretty(&request).map_err(Into::into)
+    }
+
+    /// 构造间接响应模式的请求，导出方会将响应投递到中继的`mailbox`信箱中
+    pub fn construct_indirect_request(
+        &self,
+        rp_id: String,
+        mailbox: String,
+    ) -> Result<String, AuthError> {
+        let mut request = self.build_request(rp_id, ResponseMode::Indirect);
+        request.relay = Some(mailbox);
+        let keys = ephemeral_keys(&mut request.hpke_parameters)?;
+        request.sas_commitment = Some(SasReveal::commitment(&reveal_nonce(&keys)?));
+        self.inner.save_pending(&request, keys)?;
+        serde_json::to_string_pretty(&request).map_err(Into::into)
+    }
+
+    /// 发起与另一个验证器的配对，返回会话和需要发送给对方的消息
+    pub fn start_pairing(&self, code: &str) -> Result<(PairingSession, String), AuthError> {
+        let (session, message) = PairingSession::start(code)?;
+        Ok((session, serde_json::to_string_pretty(&message)?))
+    }
+
+    /// 响应对方发起的配对，返回会话和需要发送给对方的消息
+    pub fn respond_pairing(
+        &self,
+        code: &str,
+        message: &str,
+    ) -> Result<(PairingSession, String), AuthError> {
+        let (session, reply) = PairingSession::respond(code, &serde_json::from_str(message)?)?;
+        Ok((session, serde_json::to_string_pretty(&reply)?))
+    }
+
+    /// 完成配对并保存协商出的PSK，返回psk_id
+    /// 发起方还会得到需要发送给响应方的确认消息
+    pub fn finish_pairing(
+        &self,
+        session: PairingSession,
+        message: &str,
+    ) -> Result<(String, Option<String>), AuthError> {
+        let message: PairingMessage = serde_json::from_str(message)?;
+        let (key, reply) = session.finish(&message)?;
+        self.inner.add_psk(&key.psk_id, &key.psk)?;
+        let reply = reply
+            .map(|reply| serde_json::to_string_pretty(&reply))
+            .transpose()?;
+        Ok((key.psk_id, reply))
+    }
+
+    /// 以自备份模式导出全部凭证，使用自身持久化的密钥加密，只有持有相同密钥的验证器才能恢复
+    /// 备份中同时保存请求，恢复时不依赖已发出请求的记录
+    pub fn backup(&self) -> Result<String, AuthError> {
+        let request = self.build_request(EXPORTER_RP_ID.to_string(), ResponseMode::Self_);
+        let response = self.process_request(request.clone(), request.version)?;
+        serde_json::to_string_pretty(&Backup { request, response }).map_err(Into::into)
+    }
+
+    /// 从自备份中恢复凭证，已存在的凭证会被跳过，返回新存储的凭证数量
+    pub fn restore(&self, backup: String) -> Result<usize, AuthError> {
+        let backup: Backup = serde_json::from_str(&backup)?;
+        if backup.request.mode != ResponseMode::Self_ {
+            return Err(RequestNotAllowed("不是自备份文件".to_string()));
+        }
+        check_version(backup.response.version)?;
+        check_response(&backup.response, &backup.request)?;
+        let keys = self.inner.key_pair(backup.response.hpke_parameters.kem)?;
+        // 自备份不需要核对短认证码
+        let import = self.open_response(backup.response, &backup.request, &keys, &[])?;
+        let existing: HashSet<String> = self
+            .inner
+            .get_credentials()?
+            .into_iter()
+            .map(|cred| cred.to_item().id)
+            .collect();
+        let mut count = 0;
+        for item in import
+            .header
+            .items()
+            .filter(|item| !existing.contains(&item.id))
+        {
+            let credential = StructuredSingleFileCredential::from_item(item).map_err(CodeError)?;
+            self.inner.store_credential(credential)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn build_request(&self, rp_id: String, mode: ResponseMode) -> ExportRequest {
         let (hpke_params, archive_algs) = self.inner.support_algorithms();
-        let request = ExportRequest::new(
+        // 使用PSK的套件为每个已保存的PSK各提供一次，没有PSK时不提供
+        // 套件总数不超过MAX_HPKE_PARAMETERS，PSK过多时只提供排在前面的psk_id
+        let psk_suites = hpke_params.iter().filter(|param| param.uses_psk()).count();
+        let budget = MAX_HPKE_PARAMETERS.saturating_sub(hpke_params.len() - psk_suites);
+        let psk_ids: Vec<String> = self
+            .inner
+            .psk_ids()
+            .into_iter()
+            .take(budget.checked_div(psk_suites).unwrap_or(0))
+            .collect();
+        let hpke_params = hpke_params
+            .into_iter()
+            .flat_map(|param| {
+                if !param.uses_psk() {
+                    return vec![param];
+                }
+                psk_ids
+                    .iter()
+                    .map(|id| HPKEParameters {
+                        psk_id: Some(id.clone()),
+                        ..param.clone()
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut request = ExportRequest::new(
             hpke_params,
-            ResponseMode::Direct,
+            mode,
             rp_id,
             archive_algs,
-            None,
-            None,
+            Some(self.inner.credential_types()).filter(|types| {
+                !CredentialType::ALL.iter().all(|t| types.contains(t)) && !types.is_empty()
+            }),
+            (!self.extensions.is_empty()).then(|| self.extensions.names()),
         );
-        serde_json::to_string_pretty(&request).map_err(Into::into)
+        let extension_data: Map<String, Value> = self
+            .extensions
+            .names()
+            .into_iter()
+            .filter_map(|name| {
+                let data = self.extensions.get(&name)?.request_data()?;
+                Some((name, data))
+            })
+            .collect();
+        request.extensions = (!extension_data.is_empty()).then_some(extension_data);
+        request
     }
 
     /// 处理请求，计算参数进行加密，并返回Json格式的字符串
     /// 传入收到的请求的字符串Json格式
     pub fn handle_request(&self, request: String) -> Result<String, AuthError> {
-        let request: ExportRequest = serde_json::from_str(&request)?;
-        let (mut hpke_param, archive_alg) =
-            self.match_algorithm(&request.hpke_parameters, &request.archive)?;
+        let (request, version) = self.audit_rejected(decode_request(&request))?;
+        let response = self.process_request(request, version)?;
+        serde_json::to_string_pretty(&response).map_err(Into::into)
+    }
+
+    /// 按照请求的响应模式处理请求：直接模式返回响应，间接模式将响应投递到中继
+    /// 短认证码在收到导入方的揭示消息后由[`Authenticator::verify_sas_reveal`]给出
+    pub fn respond(&self, request: String, relay: &dyn Relay) -> Result<Delivery, AuthError> {
+        let (request, version): (ExportRequest, u16) =
+            self.audit_rejected(decode_request(&request))?;
+        match request.mode {
+            ResponseMode::Direct | ResponseMode::Self_ => {
+                let response = self.process_request(request, version)?;
+                let response = serde_json::to_string_pretty(&response)?;
+                Ok(Delivery::Direct(response))
+            }
+            ResponseMode::Indirect => {
+                let mailbox = self.audit_rejected(request.relay.clone().ok_or(
+                    RequestNotAllowed("间接响应模式的请求缺少中继信箱".to_string()),
+                ))?;
+                let response = self.process_request(request, version)?;
+                relay.post(&mailbox, &serde_json::to_string_pretty(&response)?)?;
+                Ok(Delivery::Relayed(mailbox))
+            }
+        }
+    }
+
+    /// 无法处理请求时生成返回给导入方的错误消息，间接模式下投递到请求中的中继信箱
+    /// 请求无法解析时尽量从中取出nonce和中继信箱
+    pub fn reject_request(
+        &self,
+        request: &str,
+        error: &AuthError,
+        relay: &dyn Relay,
+    ) -> Result<Delivery, AuthError> {
+        let request: Value = serde_json::from_str(request).unwrap_or_default();
+        let field = |name: &str| request.get(name).and_then(Value::as_str);
+        let nonce = field("nonce").map(str::to_string);
+        let message = serde_json::to_string_pretty(&ExportError::new(nonce, error))?;
+        match (field("mode"), field("relay")) {
+            (Some("indirect"), Some(mailbox)) => {
+                relay.post(mailbox, &message)?;
+                Ok(Delivery::Relayed(mailbox.to_string()))
+            }
+            _ => Ok(Delivery::Direct(message)),
+        }
+    }
 
+    /// 从中继取回间接响应并处理，尚未投递时返回`None`
+    pub fn fetch_response(
+        &self,
+        mailbox: &str,
+        relay: &dyn Relay,
+    ) -> Result<Option<ImportResult>, AuthError> {
+        relay
+            .fetch(mailbox)?
+            .map(|response| self.handle_response(response))
+            .transpose()
+    }
+
+    /// `version`为协商后响应使用的协议版本
+    /// 无论成功与否都在审计日志中记录本次导出
+    fn process_request(
+        &self,
+        request: ExportRequest,
+        version: u16,
+    ) -> Result<ExportResponse, AuthError> {
+        let mut audit = AuditRecord::new(AuditEvent::Export);
+        let result = self.export_response(request, version, &mut audit);
+        self.inner.append_audit(audit.finish(&result))?;
+        result
+    }
+
+    /// 请求在处理前被拒绝（无法解析、版本协商失败等）时同样记录一次失败的导出
+    fn audit_rejected<R>(&self, result: Result<R, AuthError>) -> Result<R, AuthError> {
+        if result.is_err() {
+            self.inner
+                .append_audit(AuditRecord::new(AuditEvent::Export).finish(&result))?;
+        }
+        result
+    }
+
+    fn export_response(
+        &self,
+        request: ExportRequest,
+        version: u16,
+        audit: &mut AuditRecord,
+    ) -> Result<ExportResponse, AuthError> {
+        audit.rp_ids = vec![request.importer.clone()];
+        Self::verify_request(&request, version)?;
+        let backup = request.mode == ResponseMode::Self_;
+        // 策略拒绝的请求单独记录
+        let refused = |audit: &mut AuditRecord| audit.event = AuditEvent::PolicyRefused;
+        // 自备份由用户在本地发起，不受策略和用户同意的限制
+        let offered = match backup {
+            true => request.hpke_parameters.clone(),
+            false => self
+                .policy
+                .check_rp(&request.importer)
+                .and_then(|_| self.policy.check_suites(&request.hpke_parameters))
+                .inspect_err(|_| refused(audit))?,
+        };
+        let (mut hpke_param, archive_alg) = self.match_algorithm(&offered, &request.archive)?;
+        audit.negotiated(&hpke_param);
+
+        // 自备份只能加密给自身，否则任何人都能借此导出全部凭证
+        if backup && hpke_param.decode_jwk()?.pk != Some(self.inner.key_pair(hpke_param.kem)?.1) {
+            return Err(RequestNotAllowed(
+                "自备份请求只能使用验证器自身的公钥".to_string(),
+            ));
+        }
+        if !backup {
+            self.policy
+                .check_importer(&ImporterIdentity::of(&hpke_param)?)
+                .inspect_err(|_| refused(audit))?;
+        }
         let rp = &request.importer;
         let credentials = self.inner.get_credentials()?;
-        let credential = credentials
+        if backup {
+            let rp_ids: BTreeSet<String> =
+                credentials.iter().map(|cred| cred.get_rp_id()).collect();
+            audit.rp_ids = rp_ids.into_iter().collect();
+        }
+        let mut items: Vec<Item> = credentials
             .into_iter()
-            .find(|cred| cred.get_rp_id().eq(rp))
-            .ok_or(CredentialNotFound)?;
+            .filter(|cred| backup || cred.get_rp_id().eq(rp))
+            .map(|cred| cred.to_item())
+            .collect();
+        let skipped = filter_credential_types(&mut items, &request.credential_types);
+        if items.is_empty() {
+            return Err(CredentialNotFound);
+        }
+        if !backup {
+            self.ask_consent(&request, &hpke_param, &mut items)?;
+            self.policy
+                .check_count(items.len())
+                .inspect_err(|_| refused(audit))?;
+        }
+        let known = request.known_extensions.clone().unwrap_or_default();
+        let mut applied = Vec::new();
+        for extension in self.extensions.negotiate(&known) {
+            let data = request
+                .extensions
+                .as_ref()
+                .and_then(|data| data.get(extension.name()));
+            extension.on_export(&mut items, data)?;
+            applied.push(extension.name().to_string());
+        }
+        let sources: Vec<String> = items.iter().map(|item| item.id.clone()).collect();
+        let header = Header::new(
+            EXPORTER_RP_ID,
+            EXPORTER_DISPLAY_NAME,
+            vec![Account::new(unique_item_ids(items))],
+        );
         let data = archive_alg
-            .compress(&credential.get_credential())
-            .map_err(|e| CodeError(e))?;
+            .compress(&serde_json::to_vec(&header)?)
+            .map_err(CodeError)?;
 
-        let pk = &hpke_param.decode_jwk()?.pk.unwrap();
-        let (cipher, encapped_key) = self.perform_encryption(&hpke_param, &data, &pk)?;
-
-        hpke_param.encode_jwk(
-            Some(encapped_key),
-            matches!(hpke_param.mode, AuthPsk | Auth)
-                .then(|| self.inner.key_pair(hpke_param.kem).1),
-        );
+        let pk = &hpke_param
+            .decode_jwk()?
+            .pk
+            .ok_or(InvalidPublicKey(hpke_param.kem))?;
+        let sender_pk = match hpke_param.mode {
+            Auth | AuthPsk => Some(self.inner.identity_key_pair(hpke_param.kem)?.1),
+            _ => None,
+        };
+        hpke_param.encode_jwk(None, sender_pk.clone());
 
-        let response = ExportResponse {
-            version: 0,
+        // 先构造不含密文的响应计算AAD，再填入密文和封装密钥
+        let mut response = ExportResponse {
+            version,
             hpke_parameters: hpke_param,
             archive: archive_alg,
-            exporter: request.importer,
-            payload: BASE64_URL_SAFE.encode(&cipher),
+            exporter: request.importer.clone(),
+            nonce: request.nonce.clone(),
+            issued_at: request.issued_at,
+            expires_at: request.expires_at,
+            payload: Vec::new(),
+            skipped_credential_types: (!skipped.is_empty()).then_some(skipped),
+            extensions: (!applied.is_empty()).then_some(applied),
         };
-        serde_json::to_string_pretty(&response).map_err(Into::into)
+        let binding = Binding {
+            info: &request.hpke_info()?,
+            aad: &response.aad()?,
+        };
+        let (records, encapped_key, ctx) =
+            self.perform_encryption(&response.hpke_parameters, &data, pk, &binding)?;
+        // 自备份没有导入回执和短认证码
+        if !backup {
+            let sas_secret =
+                export_secret_s(&*ctx, SAS_CONTEXT, SAS_SECRET_LEN).map_err(CryptoError)?;
+            let receipt_key =
+                export_secret_s(&*ctx, RECEIPT_CONTEXT, RECEIPT_KEY_LEN).map_err(CryptoError)?;
+            let items = header
+                .items()
+                .zip(sources)
+                .map(|(item, source)| {
+                    let id = item.id.clone();
+                    Ok((ImportReceipt::item_hash(item)?, ExportedItem { id, source }))
+                })
+                .collect::<Result<_, AuthError>>()?;
+            let record = ExportRecord {
+                receipt_key: BASE64_URL_SAFE.encode(receipt_key),
+                items,
+                expires_at: request.expires_at,
+                sas_secret: BASE64_URL_SAFE.encode(sas_secret),
+                sas_commitment: request.sas_commitment.clone().unwrap_or_default(),
+            };
+            self.inner.save_export(&request.nonce, record)?;
+        }
+        response
+            .hpke_parameters
+            .encode_jwk(Some(encapped_key), sender_pk);
+        response.payload = records
+            .iter()
+            .map(|record| BASE64_URL_SAFE.encode(record))
+            .collect();
+        Ok(response)
     }
 
-    ///处理传入的Export响应，解密
-    pub fn handle_response(&self, response: String) -> Result<String, AuthError> {
-        let response: ExportResponse = serde_json::from_str(&response)?;
-        let cipher = &BASE64_URL_SAFE.decode(response.payload)?;
+    ///处理传入的Export响应，解密并解析出CXF数据
+    /// 导出方返回错误消息时给出[`ExportFailed`]，无论成功与否都在审计日志中记录本次导入
+    pub fn handle_response(&self, response: String) -> Result<ImportResult, AuthError> {
+        let mut audit = AuditRecord::new(AuditEvent::Import);
+        let result = self.import_response(response, &mut audit);
+        self.inner.append_audit(audit.finish(&result))?;
+        result
+    }
+
+    fn import_response(
+        &self,
+        response: String,
+        audit: &mut AuditRecord,
+    ) -> Result<ImportResult, AuthError> {
+        if peek_version(&response)?.1.get("error").is_some() {
+            return Err(self.export_failure(&response)?);
+        }
+        let response: ExportResponse = decode_response(&response)?;
+        let pending = self
+            .inner
+            .pending_request(&response.nonce)?
+            .ok_or(UnknownRequest)?;
+        audit.rp_ids = vec![pending.request.importer.clone()];
+        audit.negotiated(&response.hpke_parameters);
+        if pending.consumed_at.is_some() {
+            return Err(RequestConsumed);
+        }
+        if pending.request.is_expired(unix_time()) {
+            self.inner.discard_pending_keys(&response.nonce)?;
+            return Err(RequestExpired);
+        }
+        // 先确认响应使用的是请求中提供的套件，伪造的响应不能使验证器生成密钥
+        check_response(&response, &pending.request)?;
+        // 升级前保存的请求没有临时密钥，使用静态接收密钥
+        let kem = response.hpke_parameters.kem;
+        let keys = match pending.key_pair(kem)? {
+            Some(keys) => keys,
+            None if pending.keys.is_empty() => self.inner.key_pair(kem)?,
+            None => return Err(KeyNotFound(kem)),
+        };
+        let nonce = response.nonce.clone();
+        let reveal = reveal_nonce(&pending.keys)?;
+        let import = self.open_response(response, &pending.request, &keys, &reveal)?;
+        // 只有成功解密的响应才会消耗请求，伪造的响应无法使合法响应失效
+        self.inner.consume_pending(&nonce)?;
+        Ok(import)
+    }
+
+    /// 解析导出方的错误消息，消息未经认证，因此不会消耗请求
+    fn export_failure(&self, message: &str) -> Result<AuthError, AuthError> {
+        let error: ExportError = decode_response(message)?;
+        if let Some(nonce) = &error.nonce {
+            self.inner.pending_request(nonce)?.ok_or(UnknownRequest)?;
+        }
+        Ok(ExportFailed(error.error, error.message))
+    }
+
+    /// 使用发出的请求重新计算info和AAD，以`keys`中的接收私钥解密响应
+    /// 调用前应已通过[`check_response`]确认响应与请求一致
+    fn open_response(
+        &self,
+        response: ExportResponse,
+        request: &ExportRequest,
+        keys: &KeyPair,
+        reveal: &[u8],
+    ) -> Result<ImportResult, AuthError> {
+        let binding = Binding {
+            info: &request.hpke_info()?,
+            aad: &response.aad()?,
+        };
+        let records = response
+            .payload
+            .iter()
+            .map(|record| BASE64_URL_SAFE.decode(record))
+            .collect::<Result<Vec<_>, _>>()?;
         let params = &response.hpke_parameters;
-        let (sk, pk) = self.inner.key_pair(params.kem);
-        let enc = &params.decode_jwk()?.enc.unwrap();
-        let decrypted_text = self.perform_decryption(params, &cipher, &sk, &pk, enc)?;
-        let credential = response
+        let (sk, pk) = keys;
+        let enc = &params
+            .decode_jwk()?
+            .enc
+            .ok_or(CodeError("响应缺少封装密钥".to_string()))?;
+        // Auth模式的响应必须带有长度正确的导出方身份公钥
+        if matches!(params.mode, Auth | AuthPsk) {
+            let len = public_key_len(params.kem).map_err(CryptoError)?;
+            match params.decode_jwk()?.pk {
+                Some(pk) if pk.len() == len => {}
+                _ => return Err(InvalidPublicKey(params.kem)),
+            }
+        }
+        let (decrypted_text, ctx) =
+            self.perform_decryption(params, &records, sk, pk, enc, &binding)?;
+        let sas = sas_code(
+            &export_secret_r(&*ctx, SAS_CONTEXT, SAS_SECRET_LEN).map_err(CryptoError)?,
+            reveal,
+        );
+        let receipt_key =
+            export_secret_r(&*ctx, RECEIPT_CONTEXT, RECEIPT_KEY_LEN).map_err(CryptoError)?;
+        let archive = response
             .archive
             .decompress(&decrypted_text)
             .map_err(|e| CodeError(format!("Unzip Decoded error{:?}", e)))?;
-        // println!("DECRYPT:{}", String::from_utf8(credential.clone()).unwrap());
-        // self.inner
-        //     .store_credential(StructuredSingleFileCredential {
-        //         rp_id: response.exporter,
-        //         credential: credential.clone(),
-        //     })?;
-        Ok(String::from_utf8(credential).unwrap())
+        let mut header: Header = serde_json::from_slice(&archive)
+            .map_err(|e| CodeError(format!("CXF Decode error {}", e)))?;
+        // 摘要在扩展处理前计算，与导出方发送的条目一致
+        let item_hashes = header
+            .items()
+            .map(ImportReceipt::item_hash)
+            .collect::<Result<_, _>>()?;
+        let applied = response.extensions.unwrap_or_default();
+        for extension in self.extensions.negotiate(&applied) {
+            extension.on_import(&mut header)?;
+        }
+        Ok(ImportResult {
+            header,
+            skipped_types: response.skipped_credential_types.unwrap_or_default(),
+            sas,
+            confirmed: false,
+            reveal: reveal.to_vec(),
+            nonce: response.nonce,
+            receipt_key,
+            item_hashes,
+        })
+    }
+    /// 将导入的所有条目存储到内部验证器中，返回存储的条目数量
+    /// 用户未确认短认证码时拒绝存储，避免导入被中间人替换的凭证
+    pub fn commit_import(&self, import: &ImportResult) -> Result<usize, AuthError> {
+        if !import.confirmed {
+            return Err(SasNotConfirmed);
+        }
+        let mut count = 0;
+        for item in import.header.items() {
+            let credential = StructuredSingleFileCredential::from_item(item).map_err(CodeError)?;
+            self.inner.store_credential(credential)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// 生成发送给导出方的导入回执，以本次交换导出的回执密钥签名
+    /// 报告已导入时要求用户已确认短认证码
+    pub fn import_receipt(
+        &self,
+        import: &ImportResult,
+        status: ReceiptStatus,
+    ) -> Result<String, AuthError> {
+        let items = match status {
+            ReceiptStatus::Imported if !import.confirmed => return Err(SasNotConfirmed),
+            ReceiptStatus::Imported => import.item_hashes.clone(),
+            ReceiptStatus::Rejected => Vec::new(),
+        };
+        let mut receipt = ImportReceipt {
+            version: PROTOCOL_VERSION,
+            nonce: import.nonce.clone(),
+            status,
+            count: items.len(),
+            items,
+            mac: String::new(),
+        };
+        receipt.sign(&import.receipt_key)?;
+        serde_json::to_string_pretty(&receipt).map_err(Into::into)
+    }
+
+    /// 生成发送给导出方的揭示消息，导出方据此计算短认证码
+    pub fn sas_reveal(&self, import: &ImportResult) -> Result<String, AuthError> {
+        let reveal = SasReveal {
+            version: PROTOCOL_VERSION,
+            nonce: import.nonce.clone(),
+            reveal: BASE64_URL_SAFE_NO_PAD.encode(&import.reveal),
+        };
+        serde_json::to_string_pretty(&reveal).map_err(Into::into)
+    }
+
+    /// 验证导入方揭示的随机数与请求中的承诺一致，返回需要显示给用户、与导入方核对的短认证码
+    pub fn verify_sas_reveal(&self, reveal: String) -> Result<String, AuthError> {
+        let reveal: SasReveal = decode_response(&reveal)?;
+        let record = self
+            .inner
+            .export_record(&reveal.nonce)?
+            .ok_or(UnknownRequest)?;
+        let nonce = reveal.open(&record.sas_commitment)?;
+        Ok(sas_code(
+            &BASE64_URL_SAFE.decode(&record.sas_secret)?,
+            &nonce,
+        ))
+    }
+
+    /// 验证导入回执，导入成功时按`action`处理回执中列出的已导出凭证
+    /// 每次导出只接受一份有效回执
+    pub fn verify_receipt(
+        &self,
+        receipt: String,
+        action: ReceiptAction,
+    ) -> Result<ImportReceipt, AuthError> {
+        let receipt: ImportReceipt = decode_response(&receipt)?;
+        let record = self
+            .inner
+            .export_record(&receipt.nonce)?
+            .ok_or(UnknownRequest)?;
+        receipt.verify(&BASE64_URL_SAFE.decode(&record.receipt_key)?)?;
+        if receipt.count != receipt.items.len() {
+            return Err(InvalidReceipt("条目数量与摘要不一致".to_string()));
+        }
+        if receipt.items.iter().collect::<HashSet<_>>().len() != receipt.items.len() {
+            return Err(InvalidReceipt("条目摘要重复".to_string()));
+        }
+        let items = receipt
+            .items
+            .iter()
+            .map(|hash| {
+                record
+                    .items
+                    .get(hash)
+                    .ok_or(InvalidReceipt("回执中包含未导出的条目".to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if receipt.status == ReceiptStatus::Rejected && !items.is_empty() {
+            return Err(InvalidReceipt("放弃导入的回执不应包含条目".to_string()));
+        }
+        self.inner.remove_export(&receipt.nonce)?;
+        for item in items {
+            match action {
+                ReceiptAction::Keep => {}
+                ReceiptAction::Mark => self.inner.mark_migrated(&item.source)?,
+                ReceiptAction::Delete => self.inner.delete_credential(&item.source)?,
+            }
+        }
+        Ok(receipt)
     }
     // 匹配使用的算法
     // 匹配加密和压缩两个算法，分别输入两个算法的支持列表，支持列表与自身支持的列表进行比较，选取出第一个共同的算法
@@ -102,11 +724,27 @@ impl<T: InnerAuthenticator> Authenticator<T> {
     ) -> Result<(HPKEParameters, ArchiveAlgorithm), AuthError> {
         let supported = self.inner.support_algorithms();
 
-        let hpke = recv_hpke
-            .iter()
-            .find(|recv| supported.0.contains(recv))
-            .cloned()
-            .ok_or(UnsupportedAlgorithm)?;
+        // 算法套件匹配但PSK未知时记录下来，以便给出准确的错误
+        let mut unknown_psk = None;
+        let mut hpke = None;
+        for recv in recv_hpke {
+            if !supported.0.iter().any(|support| support.same_suite(recv)) {
+                continue;
+            }
+            if let Some(id) = recv.psk_id.as_ref().filter(|_| recv.uses_psk()) {
+                if self.inner.psk(id)?.is_none() {
+                    unknown_psk.get_or_insert_with(|| id.clone());
+                    continue;
+                }
+            }
+            hpke = Some(recv.clone());
+            break;
+        }
+        let hpke = match (hpke, unknown_psk) {
+            (Some(hpke), _) => hpke,
+            (None, Some(id)) => return Err(UnknownPsk(id)),
+            (None, None) => return Err(UnsupportedAlgorithm),
+        };
 
         let archive = supported
             .1
@@ -118,48 +756,284 @@ impl<T: InnerAuthenticator> Authenticator<T> {
         Ok((hpke, archive))
     }
 
-    // fn verify_request(request: &ExportRequest) -> Result<(), String> {
-    //     todo!()
-    // }
+    // 校验请求的格式，在匹配算法和读取凭证之前拒绝不合法的请求
+    // 无法识别的KEM不检查公钥，留给算法匹配处理
+    fn verify_request(request: &ExportRequest, version: u16) -> Result<(), AuthError> {
+        check_version(version)?;
+        let nonce = BASE64_URL_SAFE_NO_PAD
+            .decode(&request.nonce)
+            .map_err(|_| InvalidRequest("nonce不是有效的Base64url编码".to_string()))?;
+        if !(MIN_NONCE_LEN..=MAX_NONCE_LEN).contains(&nonce.len()) {
+            return Err(InvalidRequest("nonce长度错误".to_string()));
+        }
+        let now = unix_time();
+        if request.expires_at <= request.issued_at
+            || request.expires_at - request.issued_at > MAX_REQUEST_LIFETIME
+            || request.issued_at > now + MAX_CLOCK_SKEW
+        {
+            return Err(InvalidRequest("请求的有效期错误".to_string()));
+        }
+        if request.is_expired(now) {
+            return Err(RequestExpired);
+        }
+        check_list(
+            "hpke_parameters",
+            &request.hpke_parameters,
+            MAX_HPKE_PARAMETERS,
+        )?;
+        check_list("archive", &request.archive, MAX_ARCHIVE_ALGORITHMS)?;
+        if let Some(types) = &request.credential_types {
+            check_list("credential_types", types, MAX_CREDENTIAL_TYPES)?;
+        }
+        if let Some(names) = &request.known_extensions {
+            check_list("known_extensions", names, MAX_EXTENSIONS)?;
+        }
+        if request.extensions.as_ref().map_or(0, Map::len) > MAX_EXTENSIONS {
+            return Err(InvalidRequest("extensions过长".to_string()));
+        }
+        for param in &request.hpke_parameters {
+            match &param.psk_id {
+                Some(id) if id.is_empty() || id.len() > MAX_PSK_ID_LEN => {
+                    return Err(InvalidRequest("psk_id长度错误".to_string()))
+                }
+                None if param.uses_psk() => {
+                    return Err(InvalidRequest("PSK模式的套件缺少psk_id".to_string()))
+                }
+                _ => {}
+            }
+            let Ok(len) = public_key_len(param.kem) else {
+                continue;
+            };
+            match param.decode_jwk()?.pk {
+                Some(pk) if pk.len() == len => {}
+                _ => return Err(InvalidPublicKey(param.kem)),
+            }
+        }
+        if !is_valid_rp_id(&request.importer) {
+            return Err(InvalidRequest(format!("非法的RP ID：{}", request.importer)));
+        }
+        // 自备份之外的请求必须承诺短认证码随机数
+        if request.mode != ResponseMode::Self_ {
+            let commitment = request
+                .sas_commitment
+                .as_ref()
+                .and_then(|c| BASE64_URL_SAFE_NO_PAD.decode(c).ok());
+            if commitment.map(|c| c.len()) != Some(COMMITMENT_LEN) {
+                return Err(InvalidRequest("缺少短认证码承诺".to_string()));
+            }
+        }
+        Ok(())
+    }
 
     fn perform_encryption(
         &self,
         params: &HPKEParameters,
         data: &[u8],
         pk: &[u8],
-    ) -> Result<(Vec<u8>, Vec<u8>), AuthError> {
+        binding: &Binding,
+    ) -> Result<Sealed, AuthError> {
+        let psk = self.psk_for(params)?;
+        // 只有Auth模式需要以身份密钥认证发送方，其他模式不生成身份密钥
+        let sender = match params.mode {
+            Auth | AuthPsk => self.inner.identity_key_pair(params.kem)?,
+            _ => Default::default(),
+        };
         encrypt(
             params.kem,
             params.kdf,
             params.aead,
             data,
+            self.record_size,
             pk,
             &params.mode,
-            &self.inner.key_pair(params.kem),
+            &sender,
+            psk.as_ref()
+                .map(|(id, key)| Psk {
+                    id: id.as_bytes(),
+                    key,
+                })
+                .as_ref(),
+            binding,
         )
-        .map_err(|s| CryptoError(s))
+        .map_err(CryptoError)
     }
 
     fn perform_decryption(
         &self,
         params: &HPKEParameters,
-        cipher: &[u8],
+        records: &[Vec<u8>],
         sk: &[u8],
         pk: &[u8],
         enc: &[u8],
-    ) -> Result<Vec<u8>, AuthError> {
+        binding: &Binding,
+    ) -> Result<Opened, AuthError> {
         let decoded_jwk = params.decode_jwk()?;
+        let psk = self.psk_for(params)?;
         decrypt(
             params.kem,
             params.kdf,
             params.aead,
-            cipher,
+            records,
             sk,
             pk,
             enc,
             &params.mode,
             &decoded_jwk.pk,
+            psk.as_ref()
+                .map(|(id, key)| Psk {
+                    id: id.as_bytes(),
+                    key,
+                })
+                .as_ref(),
+            binding,
         )
-        .map_err(|s| CryptoError(s))
+        .map_err(CryptoError)
     }
+
+    /// 询问用户是否导出候选条目，只保留用户同意导出的条目
+    fn ask_consent(
+        &self,
+        request: &ExportRequest,
+        params: &HPKEParameters,
+        items: &mut Vec<Item>,
+    ) -> Result<(), AuthError> {
+        let importer = ImporterIdentity::of(params)?;
+        let consent = self.consent.consent(&ConsentRequest {
+            importer: &importer,
+            rp_id: &request.importer,
+            credential_types: request.credential_types.as_deref(),
+            candidates: items,
+        });
+        match consent {
+            Consent::Approve => {}
+            Consent::Deny => items.clear(),
+            Consent::Subset(ids) => items.retain(|item| ids.contains(&item.id)),
+        }
+        if items.is_empty() {
+            return Err(RequestNotAllowed("用户拒绝导出凭证".to_string()));
+        }
+        Ok(())
+    }
+
+    /// 套件使用PSK时按psk_id取出预共享密钥
+    fn psk_for(&self, params: &HPKEParameters) -> Result<Option<(String, Vec<u8>)>, AuthError> {
+        if !params.uses_psk() {
+            return Ok(None);
+        }
+        let id = params
+            .psk_id
+            .clone()
+            .ok_or(InvalidRequest("PSK模式的套件缺少psk_id".to_string()))?;
+        let psk = self.inner.psk(&id)?.ok_or(UnknownPsk(id.clone()))?;
+        Ok(Some((id, psk)))
+    }
+}
+
+/// 响应的版本、套件和压缩算法必须是请求中提供的
+fn check_response(response: &ExportResponse, request: &ExportRequest) -> Result<(), AuthError> {
+    check_requested(
+        response.version,
+        request.version,
+        request.supported_versions.as_deref(),
+    )?;
+    if !request.hpke_parameters.contains(&response.hpke_parameters)
+        || !request.archive.contains(&response.archive)
+    {
+        return Err(UnsupportedAlgorithm);
+    }
+    Ok(())
+}
+
+/// 请求中承诺的随机数由临时私钥派生，随临时密钥一同销毁
+fn reveal_nonce(keys: &[KeyEntry]) -> Result<Vec<u8>, AuthError> {
+    let mut hasher = Sha256::new();
+    hasher.update(REVEAL_LABEL);
+    for key in keys {
+        hasher.update(BASE64_URL_SAFE.decode(&key.sk)?);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+/// 为参数中的每个KEM生成一个临时密钥对，并将参数中的公钥替换为临时公钥
+fn ephemeral_keys(params: &mut [HPKEParameters]) -> Result<Vec<KeyEntry>, AuthError> {
+    let mut keys: Vec<KeyEntry> = Vec::new();
+    for param in params.iter_mut() {
+        let entry = match keys.iter().find(|entry| entry.kem == param.kem) {
+            Some(entry) => entry,
+            None => {
+                keys.push(KeyEntry::generate(param.kem)?);
+                &keys[keys.len() - 1]
+            }
+        };
+        param.key.pk = Some(entry.pk.clone());
+    }
+    Ok(keys)
+}
+
+/// 去除导入方不接受的凭证并返回被跳过的类型，不再包含任何凭证的条目会被整体移除
+fn filter_credential_types(
+    items: &mut Vec<Item>,
+    accepted: &Option<Vec<CredentialType>>,
+) -> Vec<CredentialType> {
+    let Some(accepted) = accepted else {
+        return Vec::new();
+    };
+    let mut skipped = BTreeSet::new();
+    for item in items.iter_mut() {
+        item.credentials.retain(|cred| {
+            let accept = accepted.contains(&cred.credential_type());
+            if !accept {
+                skipped.insert(cred.credential_type());
+            }
+            accept
+        });
+    }
+    items.retain(|item| !item.credentials.is_empty());
+    skipped.into_iter().collect()
+}
+
+/// 同一响应中的条目ID必须互不相同，内容相同的凭证会得到相同的ID，此时以序号重新派生
+fn unique_item_ids(mut items: Vec<Item>) -> Vec<Item> {
+    let mut seen = HashSet::new();
+    for (index, item) in items.iter_mut().enumerate() {
+        while !seen.insert(item.id.clone()) {
+            let digest = Sha256::digest(format!("{}#{}", item.id, index));
+            item.id = BASE64_URL_SAFE_NO_PAD.encode(&digest[..16]);
+        }
+    }
+    items
+}
+
+/// 列表不能为空、超出上限或包含重复项
+fn check_list<T: PartialEq>(name: &str, list: &[T], max: usize) -> Result<(), AuthError> {
+    if list.is_empty() {
+        return Err(InvalidRequest(format!("{}为空", name)));
+    }
+    if list.len() > max {
+        return Err(InvalidRequest(format!("{}过长", name)));
+    }
+    if list.iter().enumerate().any(|(i, a)| list[..i].contains(a)) {
+        return Err(InvalidRequest(format!("{}中存在重复项", name)));
+    }
+    Ok(())
+}
+
+/// RP ID为域名：以`.`分隔的标签由字母、数字和`-`组成，标签不能以`-`开头或结尾
+fn is_valid_rp_id(rp_id: &str) -> bool {
+    !rp_id.is_empty()
+        && rp_id.len() <= 253
+        && rp_id.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
 }
@@ -0,0 +1,217 @@
+//! # 配对
+//! 两个验证器输入相同的一次性配对码，通过平衡PAKE（ristretto255上的CPace）协商出高熵的预共享密钥，
+//! 保存到PSK存储中供Psk和AuthPsk模式使用
+//!
+//! 配对共交换三条消息：发起方发送`start`，响应方回复`response`并附带确认值，发起方验证后回复`confirm`。
+//! 配对码不一致时双方得到不同的会话密钥，确认值校验失败，不会保存任何密钥；
+//! 中间人每次配对只能在线猜测一次配对码
+use crate::authenticator::error::AuthenticatorError as AuthError;
+use base64::prelude::{BASE64_URL_SAFE, BASE64_URL_SAFE_NO_PAD};
+use base64::Engine;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::traits::IsIdentity;
+use curve25519_dalek::Scalar;
+use hkdf::Hkdf;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+
+/// 配对码的最短长度
+pub const MIN_CODE_LEN: usize = 6;
+const DSI: &[u8] = b"fido-cx CPace-Ristretto255";
+const SID_LEN: usize = 16;
+const PSK_LEN: usize = 32;
+const PSK_ID_LEN: usize = 12;
+const CONFIRM_LEN: usize = 32;
+const CONFIRM_INITIATOR: &[u8] = b"fido-cx pairing confirm initiator";
+const CONFIRM_RESPONDER: &[u8] = b"fido-cx pairing confirm responder";
+
+/// 配对过程中交换的消息，点和随机数均为Base64url编码
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "step", rename_all = "kebab-case")]
+pub enum PairingMessage {
+    Start { sid: String, share: String },
+    Response { share: String, confirm: String },
+    Confirm { confirm: String },
+}
+
+/// 配对得到的预共享密钥
+pub struct PairedKey {
+    pub psk_id: String,
+    pub psk: Vec<u8>,
+}
+
+/// 尚未完成的配对
+pub enum PairingSession {
+    /// 发起方等待响应
+    Initiator {
+        sid: Vec<u8>,
+        secret: Scalar,
+        share: CompressedRistretto,
+    },
+    /// 响应方等待确认
+    Responder { isk: Vec<u8> },
+}
+
+impl PairingSession {
+    /// 发起配对，返回会话和需要发送给响应方的消息
+    pub fn start(code: &str) -> Result<(Self, PairingMessage), AuthError> {
+        check_code(code)?;
+        let mut sid = vec![0u8; SID_LEN];
+        rand::thread_rng().fill_bytes(&mut sid);
+        let secret = random_scalar();
+        let share = (generator(code, &sid) * secret).compress();
+        let message = PairingMessage::Start {
+            sid: BASE64_URL_SAFE.encode(&sid),
+            share: BASE64_URL_SAFE.encode(share.as_bytes()),
+        };
+        Ok((Self::Initiator { sid, secret, share }, message))
+    }
+
+    /// 响应发起方的消息，返回会话和需要发送给发起方的消息
+    pub fn respond(
+        code: &str,
+        message: &PairingMessage,
+    ) -> Result<(Self, PairingMessage), AuthError> {
+        check_code(code)?;
+        let PairingMessage::Start { sid, share } = message else {
+            return Err(AuthError::PairingFailed("应为配对发起消息".to_string()));
+        };
+        let sid = BASE64_URL_SAFE.decode(sid)?;
+        if sid.len() != SID_LEN {
+            return Err(AuthError::PairingFailed("会话标识长度错误".to_string()));
+        }
+        let (peer_bytes, peer) = decode_share(share)?;
+        let secret = random_scalar();
+        let own = (generator(code, &sid) * secret).compress();
+        let isk = session_key(&sid, &(peer * secret), &peer_bytes, own.as_bytes())?;
+        let message = PairingMessage::Response {
+            share: BASE64_URL_SAFE.encode(own.as_bytes()),
+            confirm: BASE64_URL_SAFE.encode(expand(&isk, CONFIRM_RESPONDER, CONFIRM_LEN)),
+        };
+        Ok((Self::Responder { isk }, message))
+    }
+
+    /// 处理对方的下一条消息，确认值正确时返回配对得到的密钥
+    /// 发起方还会得到需要发送给响应方的确认消息
+    pub fn finish(
+        self,
+        message: &PairingMessage,
+    ) -> Result<(PairedKey, Option<PairingMessage>), AuthError> {
+        match (self, message) {
+            (
+                Self::Initiator { sid, secret, share },
+                PairingMessage::Response {
+                    share: peer,
+                    confirm,
+                },
+            ) => {
+                let (peer_bytes, peer) = decode_share(peer)?;
+                let isk = session_key(&sid, &(peer * secret), share.as_bytes(), &peer_bytes)?;
+                verify_confirm(&isk, CONFIRM_RESPONDER, confirm)?;
+                let reply = PairingMessage::Confirm {
+                    confirm: BASE64_URL_SAFE.encode(expand(&isk, CONFIRM_INITIATOR, CONFIRM_LEN)),
+                };
+                Ok((paired_key(&isk), Some(reply)))
+            }
+            (Self::Responder { isk }, PairingMessage::Confirm { confirm }) => {
+                verify_confirm(&isk, CONFIRM_INITIATOR, confirm)?;
+                Ok((paired_key(&isk), None))
+            }
+            _ => Err(AuthError::PairingFailed("配对消息顺序错误".to_string())),
+        }
+    }
+}
+
+fn check_code(code: &str) -> Result<(), AuthError> {
+    if code.chars().count() < MIN_CODE_LEN {
+        return Err(AuthError::PairingFailed(format!(
+            "配对码至少为{}个字符",
+            MIN_CODE_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// 由配对码和会话标识派生的生成元，不知道配对码时无法得到它与基点的离散对数关系
+fn generator(code: &str, sid: &[u8]) -> RistrettoPoint {
+    let mut hasher = Sha512::new();
+    update_field(&mut hasher, DSI);
+    update_field(&mut hasher, code.as_bytes());
+    update_field(&mut hasher, sid);
+    RistrettoPoint::from_hash(hasher)
+}
+
+fn random_scalar() -> Scalar {
+    let mut bytes = [0u8; 64];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    Scalar::from_bytes_mod_order_wide(&bytes)
+}
+
+fn decode_share(share: &str) -> Result<(Vec<u8>, RistrettoPoint), AuthError> {
+    let bytes = BASE64_URL_SAFE.decode(share)?;
+    let point = CompressedRistretto::from_slice(&bytes)
+        .ok()
+        .and_then(|point| point.decompress())
+        .filter(|point| !point.is_identity())
+        .ok_or(AuthError::PairingFailed("无效的配对份额".to_string()))?;
+    Ok((bytes, point))
+}
+
+/// 会话密钥，覆盖会话标识、共享点和按发起方、响应方顺序排列的双方份额
+fn session_key(
+    sid: &[u8],
+    shared: &RistrettoPoint,
+    initiator: &[u8],
+    responder: &[u8],
+) -> Result<Vec<u8>, AuthError> {
+    if shared.is_identity() {
+        return Err(AuthError::PairingFailed("无效的共享点".to_string()));
+    }
+    let mut hasher = Sha512::new();
+    update_field(&mut hasher, &[DSI, b"_ISK"].concat());
+    update_field(&mut hasher, sid);
+    update_field(&mut hasher, shared.compress().as_bytes());
+    update_field(&mut hasher, initiator);
+    update_field(&mut hasher, responder);
+    Ok(hasher.finalize().to_vec())
+}
+
+fn update_field(hasher: &mut Sha512, field: &[u8]) {
+    hasher.update((field.len() as u32).to_be_bytes());
+    hasher.update(field);
+}
+
+fn expand(isk: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    let mut out = vec![0u8; len];
+    Hkdf::<Sha256>::new(None, isk)
+        .expand(info, &mut out)
+        .expect("HKDF输出长度在范围内");
+    out
+}
+
+fn paired_key(isk: &[u8]) -> PairedKey {
+    PairedKey {
+        psk_id: format!(
+            "pair-{}",
+            BASE64_URL_SAFE_NO_PAD.encode(expand(isk, b"fido-cx pairing psk_id", PSK_ID_LEN))
+        ),
+        psk: expand(isk, b"fido-cx pairing psk", PSK_LEN),
+    }
+}
+
+/// 以常数时间比较确认值
+fn verify_confirm(isk: &[u8], label: &[u8], confirm: &str) -> Result<(), AuthError> {
+    let expected = expand(isk, label, CONFIRM_LEN);
+    let received = BASE64_URL_SAFE.decode(confirm)?;
+    let diff = expected
+        .iter()
+        .zip(&received)
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    if received.len() != expected.len() || diff != 0 {
+        return Err(AuthError::PairingFailed(
+            "确认值不匹配，配对码可能不一致".to_string(),
+        ));
+    }
+    Ok(())
+}
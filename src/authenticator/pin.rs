@@ -1,44 +1,107 @@
-use crate::authenticator::crypto::gen_key_pair;
+use crate::authenticator::audit::{AuditLog, AuditRecord};
+use crate::authenticator::crypto::{gen_key_pair, public_key_len};
 use crate::authenticator::error::AuthenticatorError as AuthError;
 use crate::authenticator::inner::InnerAuthenticator;
+use crate::authenticator::keystore::{
+    ExportRecord, KeyEntry, KeyPair, KeyStore, KeyStoreHandle, PendingRequest,
+};
 use crate::authenticator::protocol::archive::ArchiveAlgorithm;
 use crate::authenticator::protocol::credential::{Credential, StructuredSingleFileCredential};
-use crate::authenticator::protocol::hpke_format::HPKEMode::Auth;
+use crate::authenticator::protocol::cxf::CredentialType;
+use crate::authenticator::protocol::hpke_format::HPKEMode::{Auth, AuthPsk};
 use crate::authenticator::protocol::hpke_format::{HPKEMode, HPKEParameters, JWKS};
+use crate::authenticator::protocol::request::ExportRequest;
+use crate::authenticator::unix_time;
 use base64::prelude::BASE64_URL_SAFE;
 use base64::Engine;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fs;
+use std::path::{Path, PathBuf};
 const SUPPORTED_KEMS: &[u16] = &[0x10, 0x11, 0x12];
 const DEFAULT_KDF: u16 = 1;
 const DEFAULT_AEAD: u16 = 1;
+/// RFC 9180要求PSK至少包含32字节的熵
+const MIN_PSK_LEN: usize = 32;
 pub struct PinInner {
     pub keys: HashMap<u16, (Vec<u8>, Vec<u8>)>,
     pub algorithms: Vec<HPKEParameters>,
+    /// 存放.cx凭证文件的目录，默认为当前目录
+    pub cred_dir: PathBuf,
+    pub credential_types: Vec<CredentialType>,
+    /// 密钥库中的其他状态，如已发出的导出请求
+    store: RefCell<KeyStore>,
+    /// 为`None`时状态只保存在内存中
+    handle: Option<KeyStoreHandle>,
 }
 
-impl PinInner {
-    pub fn default() -> PinInner {
-        let mut keys = HashMap::new();
-        let mut algorithms = Vec::new();
-
+impl Default for PinInner {
+    /// 为每个支持的KEM生成新的密钥对，不进行持久化
+    fn default() -> PinInner {
+        let keys = SUPPORTED_KEMS
+            .iter()
+            .map(|kem| (*kem, gen_key_pair(*kem).unwrap()))
+            .collect();
+        let inner = PinInner::from_keys(keys);
         for kem in SUPPORTED_KEMS {
-            let (sk, pk) = gen_key_pair(*kem).unwrap();
-            keys.insert(*kem, (sk, pk.clone()));
-            algorithms.push(HPKEParameters {
-                kem: *kem,
-                mode: Auth,
+            inner.record_key(*kem);
+        }
+        inner
+    }
+}
+
+impl PinInner {
+    /// 从密钥库文件中加载密钥对，只为缺失的KEM生成新密钥，销毁过期请求的临时密钥并写回文件
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<PinInner, AuthError> {
+        let mut store = KeyStore::load(&path)?;
+        let discarded = store.discard_expired_keys();
+        if store.ensure_keys(SUPPORTED_KEMS)? || discarded {
+            store.save(&path)?;
+        }
+        PinInner::from_store(store, KeyStoreHandle::plain(path))
+    }
+    /// 使用PIN解锁加密的密钥库，只为缺失的KEM生成新密钥，并以加密格式写回文件
+    pub fn unlock<P: AsRef<Path>>(path: P, pin: &str) -> Result<PinInner, AuthError> {
+        let (mut store, key) = KeyStore::unlock(&path, pin)?;
+        store.discard_expired_keys();
+        store.ensure_keys(SUPPORTED_KEMS)?;
+        store.seal(&path, &key)?;
+        PinInner::from_store(store, KeyStoreHandle::sealed(path, key))
+    }
+    fn from_store(store: KeyStore, handle: KeyStoreHandle) -> Result<PinInner, AuthError> {
+        let mut inner = PinInner::from_keys(store.key_pairs()?);
+        inner.store = RefCell::new(store);
+        inner.handle = Some(handle);
+        Ok(inner)
+    }
+    /// 每个KEM优先使用AuthPsk模式，请求中只为已配对的PSK提供该模式
+    fn from_keys(keys: HashMap<u16, KeyPair>) -> PinInner {
+        let algorithms = SUPPORTED_KEMS
+            .iter()
+            .filter_map(|kem| keys.get(kem).map(|(_, pk)| (*kem, pk)))
+            .flat_map(|(kem, pk)| [AuthPsk, Auth].map(|mode| (kem, pk, mode)))
+            .map(|(kem, pk, mode)| HPKEParameters {
+                kem,
+                mode,
                 kdf: DEFAULT_KDF,
                 aead: DEFAULT_AEAD,
                 key: JWKS {
                     enc: None,
-                    pk: Some(BASE64_URL_SAFE.encode(&pk)),
+                    pk: Some(BASE64_URL_SAFE.encode(pk)),
                 },
-            });
+                psk_id: None,
+            })
+            .collect();
+        PinInner {
+            keys,
+            algorithms,
+            cred_dir: PathBuf::from("."),
+            credential_types: CredentialType::ALL.to_vec(),
+            store: RefCell::default(),
+            handle: None,
         }
-        PinInner { keys, algorithms }
     }
     pub fn new(kem: u16, kdf: u16, aead: u16, mode: &HPKEMode) -> Self {
         let mut keys = HashMap::new();
@@ -55,15 +118,37 @@ impl PinInner {
                 enc: None,
                 pk: Some(BASE64_URL_SAFE.encode(&k.1)),
             },
+            psk_id: None,
         }];
-        PinInner {
+        let inner = PinInner {
             keys,
             algorithms: algors,
+            cred_dir: PathBuf::from("."),
+            credential_types: CredentialType::ALL.to_vec(),
+            store: RefCell::default(),
+            handle: None,
+        };
+        inner.record_key(kem);
+        inner
+    }
+    /// 记录只保存在内存中的密钥的生成，新的审计日志不会出错
+    fn record_key(&self, kem: u16) {
+        let _ = self
+            .store
+            .borrow_mut()
+            .audit
+            .append(AuditRecord::key_generated(kem));
+    }
+    /// 将状态写回密钥库文件
+    fn persist(&self) -> Result<(), AuthError> {
+        match &self.handle {
+            Some(handle) => handle.save(&self.store.borrow()),
+            None => Ok(()),
         }
     }
     pub fn get_cred_lis(&self) -> HashMap<String, StructuredSingleFileCredential> {
         let mut creds = HashMap::new();
-        if let Ok(path) = self.get_cx_files_in_current_dir() {
+        if let Ok(path) = self.get_cx_files() {
             for p in path {
                 if let Ok(credential) = StructuredSingleFileCredential::from_file(&p) {
                     creds.insert(p, credential);
@@ -72,17 +157,13 @@ impl PinInner {
         }
         creds
     }
-    fn get_cx_files_in_current_dir(&self) -> Result<Vec<String>, AuthError> {
-        // 获取当前目录
-        let current_dir =
-            std::env::current_dir().map_err(|e| AuthError::InternalError(e.to_string()))?;
-
+    fn get_cx_files(&self) -> Result<Vec<String>, AuthError> {
         // 用于存储匹配的文件路径
         let mut cx_files = Vec::new();
 
-        // 遍历当前目录中的所有文件和子目录（非递归）
+        // 遍历凭证目录中的所有文件和子目录（非递归）
         for entry in
-            fs::read_dir(current_dir).map_err(|e| AuthError::InternalError(e.to_string()))?
+            fs::read_dir(&self.cred_dir).map_err(|e| AuthError::InternalError(e.to_string()))?
         {
             let entry = entry.map_err(|e| AuthError::InternalError(e.to_string()))?; // 解析目录条目
             let path = entry.path(); // 获取 PathBuf
@@ -103,6 +184,9 @@ impl InnerAuthenticator for PinInner {
     fn support_algorithms(&self) -> (Vec<HPKEParameters>, Vec<ArchiveAlgorithm>) {
         (self.algorithms.clone(), vec![ArchiveAlgorithm::Deflate])
     }
+    fn credential_types(&self) -> Vec<CredentialType> {
+        self.credential_types.clone()
+    }
     fn get_credentials(&self) -> Result<Vec<impl Credential>, AuthError> {
         //下面这段在性能测试时使用，避免IO操作
         // Ok(vec![
@@ -124,12 +208,135 @@ impl InnerAuthenticator for PinInner {
             rp_id: credential.get_rp_id(),
             credential: credential.get_credential(),
         }
-        .to_file(&(random_string + ".cx"))
-        .map_err(|err| AuthError::InternalError(err))?;
+        .to_file(self.cred_dir.join(random_string + ".cx"))
+        .map_err(AuthError::InternalError)?;
         Ok(())
     }
 
-    fn key_pair(&self, kem: u16) -> (Vec<u8>, Vec<u8>) {
-        self.keys.get(&kem).cloned().expect("Key pair not found")
+    /// 缺少本实现支持的KEM的密钥时生成新的密钥对，保存到密钥库并记录到审计日志
+    fn key_pair(&self, kem: u16) -> Result<KeyPair, AuthError> {
+        if let Some(pair) = self.keys.get(&kem) {
+            return Ok(pair.clone());
+        }
+        if !SUPPORTED_KEMS.contains(&kem) {
+            return Err(AuthError::KeyNotFound(kem));
+        }
+        let generated = self.store.borrow_mut().ensure_keys(&[kem])?;
+        if generated {
+            self.persist()?;
+        }
+        self.store
+            .borrow()
+            .key_pair(kem)?
+            .ok_or(AuthError::KeyNotFound(kem))
+    }
+
+    fn save_pending(&self, request: &ExportRequest, keys: Vec<KeyEntry>) -> Result<(), AuthError> {
+        self.store.borrow_mut().add_pending(request.clone(), keys);
+        self.persist()
+    }
+
+    fn pending_request(&self, nonce: &str) -> Result<Option<PendingRequest>, AuthError> {
+        Ok(self.store.borrow().pending.get(nonce).cloned())
+    }
+
+    /// 身份密钥在首次使用时生成并保存到密钥库
+    fn identity_key_pair(&self, kem: u16) -> Result<KeyPair, AuthError> {
+        if public_key_len(kem).is_err() {
+            return Err(AuthError::KeyNotFound(kem));
+        }
+        let (pair, generated) = self.store.borrow_mut().identity_key_pair(kem)?;
+        if generated {
+            self.persist()?;
+        }
+        Ok(pair)
+    }
+
+    fn psk_ids(&self) -> Vec<String> {
+        self.store.borrow().psks.keys().cloned().collect()
+    }
+
+    fn psk(&self, psk_id: &str) -> Result<Option<Vec<u8>>, AuthError> {
+        self.store
+            .borrow()
+            .psks
+            .get(psk_id)
+            .map(|psk| BASE64_URL_SAFE.decode(psk))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    fn add_psk(&self, psk_id: &str, psk: &[u8]) -> Result<(), AuthError> {
+        if psk.len() < MIN_PSK_LEN {
+            return Err(AuthError::CryptoError(format!(
+                "预共享密钥至少为{}字节",
+                MIN_PSK_LEN
+            )));
+        }
+        self.store
+            .borrow_mut()
+            .psks
+            .insert(psk_id.to_string(), BASE64_URL_SAFE.encode(psk));
+        self.persist()
+    }
+
+    fn consume_pending(&self, nonce: &str) -> Result<(), AuthError> {
+        if let Some(pending) = self.store.borrow_mut().pending.get_mut(nonce) {
+            pending.consumed_at = Some(unix_time());
+            pending.keys.clear();
+        }
+        self.persist()
+    }
+
+    fn discard_pending_keys(&self, nonce: &str) -> Result<(), AuthError> {
+        if let Some(pending) = self.store.borrow_mut().pending.get_mut(nonce) {
+            pending.keys.clear();
+        }
+        self.persist()
+    }
+
+    fn save_export(&self, nonce: &str, record: ExportRecord) -> Result<(), AuthError> {
+        self.store.borrow_mut().add_export(nonce, record);
+        self.persist()
+    }
+
+    fn export_record(&self, nonce: &str) -> Result<Option<ExportRecord>, AuthError> {
+        Ok(self.store.borrow().exports.get(nonce).cloned())
+    }
+
+    fn remove_export(&self, nonce: &str) -> Result<(), AuthError> {
+        self.store.borrow_mut().exports.remove(nonce);
+        self.persist()
+    }
+
+    fn delete_credential(&self, id: &str) -> Result<(), AuthError> {
+        let path = self
+            .get_cred_lis()
+            .into_iter()
+            .find(|(_, cred)| cred.to_item().id == id)
+            .map(|(path, _)| path)
+            .ok_or(AuthError::CredentialNotFound)?;
+        fs::remove_file(path).map_err(|e| AuthError::InternalError(e.to_string()))
+    }
+
+    fn mark_migrated(&self, id: &str) -> Result<(), AuthError> {
+        self.store
+            .borrow_mut()
+            .migrated
+            .insert(id.to_string(), unix_time());
+        self.persist()
+    }
+
+    fn is_migrated(&self, id: &str) -> bool {
+        self.store.borrow().migrated.contains_key(id)
+    }
+
+    fn append_audit(&self, record: AuditRecord) -> Result<(), AuthError> {
+        self.store.borrow_mut().audit.append(record)?;
+        self.persist()
+    }
+
+    fn audit_log(&self) -> AuditLog {
+        self.store.borrow().audit.clone()
     }
 }
@@ -0,0 +1,137 @@
+//! # 导出策略
+//! 导出方处理请求时检查的规则，从JSON配置文件加载，未配置的规则不做限制。
+//! 任何一条规则拒绝请求时返回`RequestNotAllowed`，错误信息中包含触发的规则
+//!
+//! RP模式可以是完整的RP ID、`*.example.com`（只匹配子域名）或`*`；
+//! 导入方身份为其公钥指纹或配对得到的psk_id，拒绝列表优先于允许列表；
+//! 导入方为每个请求生成临时密钥时公钥指纹每次不同，应使用psk_id
+use crate::authenticator::consent::ImporterIdentity;
+use crate::authenticator::error::AuthenticatorError as AuthError;
+use crate::authenticator::protocol::hpke_format::{HPKEMode, HPKEParameters};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// 默认的策略文件路径
+pub const DEFAULT_POLICY_PATH: &str = "policy.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Suite {
+    pub kem: u16,
+    pub kdf: u16,
+    pub aead: u16,
+}
+
+impl From<&HPKEParameters> for Suite {
+    fn from(param: &HPKEParameters) -> Self {
+        Self {
+            kem: param.kem,
+            kdf: param.kdf,
+            aead: param.aead,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// 允许导出的RP，为空时允许所有RP
+    pub allow_rps: Vec<String>,
+    pub deny_rps: Vec<String>,
+    /// 允许的导入方，为空时允许所有导入方
+    pub allow_importers: Vec<String>,
+    pub deny_importers: Vec<String>,
+    /// 只接受使用预共享密钥的HPKE模式（Psk、AuthPsk）
+    pub require_psk: bool,
+    /// 只接受认证导出方身份的HPKE模式（Auth、AuthPsk）
+    pub require_auth: bool,
+    /// 只使用这些算法套件，为空时不限制
+    pub required_suites: Vec<Suite>,
+    /// 单个请求最多导出的凭证数量
+    pub max_credentials: Option<usize>,
+}
+
+impl Policy {
+    /// 从文件加载策略，文件不存在时返回不做限制的策略
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, AuthError> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn check_rp(&self, rp_id: &str) -> Result<(), AuthError> {
+        if let Some(pattern) = self.deny_rps.iter().find(|p| rp_matches(p, rp_id)) {
+            return Err(refuse(format!("deny_rps: {}", pattern)));
+        }
+        if !self.allow_rps.is_empty() && !self.allow_rps.iter().any(|p| rp_matches(p, rp_id)) {
+            return Err(refuse(format!("allow_rps: {}不在允许列表中", rp_id)));
+        }
+        Ok(())
+    }
+
+    /// 去掉请求中不满足模式和套件要求的HPKE参数
+    pub fn check_suites(
+        &self,
+        params: &[HPKEParameters],
+    ) -> Result<Vec<HPKEParameters>, AuthError> {
+        let mut allowed = params.to_vec();
+        if self.require_psk {
+            allowed.retain(|param| matches!(param.mode, HPKEMode::Psk | HPKEMode::AuthPsk));
+            if allowed.is_empty() {
+                return Err(refuse("require_psk".to_string()));
+            }
+        }
+        if self.require_auth {
+            allowed.retain(|param| matches!(param.mode, HPKEMode::Auth | HPKEMode::AuthPsk));
+            if allowed.is_empty() {
+                return Err(refuse("require_auth".to_string()));
+            }
+        }
+        if !self.required_suites.is_empty() {
+            allowed.retain(|param| self.required_suites.contains(&Suite::from(param)));
+            if allowed.is_empty() {
+                return Err(refuse("required_suites".to_string()));
+            }
+        }
+        Ok(allowed)
+    }
+
+    pub fn check_importer(&self, importer: &ImporterIdentity) -> Result<(), AuthError> {
+        if let Some(id) = self.deny_importers.iter().find(|id| importer.matches(id)) {
+            return Err(refuse(format!("deny_importers: {}", id)));
+        }
+        if !self.allow_importers.is_empty()
+            && !self.allow_importers.iter().any(|id| importer.matches(id))
+        {
+            return Err(refuse(format!(
+                "allow_importers: {}不在允许列表中",
+                importer.key_fingerprint
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn check_count(&self, count: usize) -> Result<(), AuthError> {
+        match self.max_credentials {
+            Some(max) if count > max => Err(refuse(format!("max_credentials: {}", max))),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn refuse(rule: String) -> AuthError {
+    AuthError::RequestNotAllowed(format!("违反策略规则 {}", rule))
+}
+
+fn rp_matches(pattern: &str, rp_id: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let rp_id = rp_id.to_ascii_lowercase();
+    match pattern.strip_prefix("*") {
+        Some("") => true,
+        Some(suffix) if suffix.starts_with('.') => rp_id.ends_with(suffix),
+        _ => pattern == rp_id,
+    }
+}
@@ -26,10 +26,8 @@ fn deflate_compress(data: &[u8]) -> Result<Vec<u8>, String> {
     let mut encoder = DeflateEncoder::new(&mut compressed_data, Compression::default());
     encoder
         .write_all(data)
-        .map_err(|e| format!("zip error:{}", e.to_string()))?;
-    encoder
-        .finish()
-        .map_err(|e| format!("zip error:{}", e.to_string()))?;
+        .map_err(|e| format!("zip error:{}", e))?;
+    encoder.finish().map_err(|e| format!("zip error:{}", e))?;
     Ok(compressed_data)
 }
 
@@ -38,6 +36,6 @@ fn deflate_decompress(data: &[u8]) -> Result<Vec<u8>, String> {
     let mut decoder = DeflateDecoder::new(data);
     decoder
         .read_to_end(&mut compressed_data)
-        .map_err(|e| format!("zip error:{}", e.to_string()))?;
+        .map_err(|e| format!("zip error:{}", e))?;
     Ok(compressed_data)
 }
@@ -1,5 +1,6 @@
 //! # FIDO Credential
-//! Note: 为了简便，Credential没有按照CXF的格式规定进行
+//! Note: 为了简便，存储的凭证没有按照CXF的格式规定进行，导出时通过[`Credential::to_item`]转换为CXF条目
+use crate::authenticator::protocol::cxf::Item;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read, Write};
 use std::path::Path;
@@ -7,6 +8,9 @@ use std::path::Path;
 pub trait Credential {
     fn get_credential(&self) -> Vec<u8>;
     fn get_rp_id(&self) -> String;
+    fn to_item(&self) -> Item {
+        Item::from_stored(&self.get_rp_id(), &self.get_credential())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -29,6 +33,13 @@ impl StructuredSingleFileCredential {
     pub fn new(rp_id: String, credential: Vec<u8>) -> Self {
         Self { rp_id, credential }
     }
+    /// 以CXF条目的JSON作为凭证内容
+    pub fn from_item(item: &Item) -> Result<Self, String> {
+        Ok(Self {
+            rp_id: item.rp_id(),
+            credential: serde_json::to_vec(item).map_err(|e| e.to_string())?,
+        })
+    }
     /// 从单个文件中读取凭证
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
         let file = File::open(path).map_err(|e| e.to_string())?;
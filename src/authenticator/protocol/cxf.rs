@@ -0,0 +1,379 @@
+//! # Credential Exchange Format
+//! CXF数据模型，导出响应中压缩并加密的内容即为序列化后的[`Header`]
+//!
+//! 字段命名与CXF规范的JSON表示保持一致(camelCase)，凭证类型通过`type`字段区分
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+pub const CXF_VERSION: Version = Version { major: 1, minor: 0 };
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Header {
+    pub version: Version,
+    pub exporter_rp_id: String,
+    pub exporter_display_name: String,
+    pub timestamp: u64,
+    pub accounts: Vec<Account>,
+}
+
+impl Header {
+    pub fn new(exporter_rp_id: &str, exporter_display_name: &str, accounts: Vec<Account>) -> Self {
+        Self {
+            version: CXF_VERSION,
+            exporter_rp_id: exporter_rp_id.to_string(),
+            exporter_display_name: exporter_display_name.to_string(),
+            timestamp: crate::authenticator::unix_time(),
+            accounts,
+        }
+    }
+
+    /// 所有账户中的条目
+    pub fn items(&self) -> impl Iterator<Item = &Item> {
+        self.accounts
+            .iter()
+            .flat_map(|account| account.items.iter())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Account {
+    pub id: String,
+    pub username: String,
+    pub email: String,
+    pub full_name: Option<String>,
+    #[serde(default)]
+    pub collections: Vec<Collection>,
+    pub items: Vec<Item>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extensions: Vec<CxfExtension>,
+}
+
+impl Account {
+    /// 以随机ID创建只包含条目的账户
+    pub fn new(items: Vec<Item>) -> Self {
+        let mut id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+        Self {
+            id: BASE64_URL_SAFE_NO_PAD.encode(id),
+            username: String::new(),
+            email: String::new(),
+            full_name: None,
+            collections: Vec::new(),
+            items,
+            extensions: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Collection {
+    pub id: String,
+    pub creation_at: Option<u64>,
+    pub modified_at: Option<u64>,
+    pub title: String,
+    pub subtitle: Option<String>,
+    #[serde(default)]
+    pub items: Vec<LinkedItem>,
+    #[serde(default)]
+    pub sub_collections: Vec<Collection>,
+}
+
+/// 集合中对条目的引用
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LinkedItem {
+    pub item: String,
+    pub account: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Item {
+    pub id: String,
+    pub creation_at: Option<u64>,
+    pub modified_at: Option<u64>,
+    pub title: String,
+    pub subtitle: Option<String>,
+    pub favorite: Option<bool>,
+    pub scope: Option<CredentialScope>,
+    pub credentials: Vec<TypedCredential>,
+    pub tags: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extensions: Vec<CxfExtension>,
+}
+
+impl Item {
+    /// 将存储的凭证转换为条目：内容是条目的JSON时直接解析，否则作为该RP下的笔记凭证
+    pub fn from_stored(rp_id: &str, data: &[u8]) -> Self {
+        serde_json::from_slice(data).unwrap_or_else(|_| Self::note(rp_id, data))
+    }
+
+    fn note(rp_id: &str, data: &[u8]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(rp_id.as_bytes());
+        hasher.update([0]);
+        hasher.update(data);
+        Self {
+            id: BASE64_URL_SAFE_NO_PAD.encode(&hasher.finalize()[..16]),
+            creation_at: None,
+            modified_at: None,
+            title: rp_id.to_string(),
+            subtitle: None,
+            favorite: None,
+            scope: Some(CredentialScope {
+                urls: vec![rp_id.to_string()],
+                android_apps: Vec::new(),
+            }),
+            credentials: vec![TypedCredential::Note(NoteCredential {
+                content: EditableField::new(
+                    FieldType::String,
+                    String::from_utf8_lossy(data).to_string(),
+                ),
+            })],
+            tags: None,
+            extensions: Vec::new(),
+        }
+    }
+
+    /// 条目所属的RP，优先取作用域中的第一个URL
+    pub fn rp_id(&self) -> String {
+        self.scope
+            .as_ref()
+            .and_then(|scope| scope.urls.first())
+            .cloned()
+            .unwrap_or_else(|| self.title.clone())
+    }
+}
+
+/// 账户或条目上的扩展数据，除名称外的字段原样保留，无法识别的扩展不会导致解析失败
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CxfExtension {
+    pub name: String,
+    #[serde(flatten)]
+    pub data: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialScope {
+    pub urls: Vec<String>,
+    #[serde(default)]
+    pub android_apps: Vec<AndroidAppIdentifier>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AndroidAppIdentifier {
+    pub bundle_id: String,
+    pub certificate: Option<String>,
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EditableField {
+    pub id: Option<String>,
+    pub field_type: FieldType,
+    pub value: String,
+    pub label: Option<String>,
+}
+
+impl EditableField {
+    pub fn new(field_type: FieldType, value: String) -> Self {
+        Self {
+            id: None,
+            field_type,
+            value,
+            label: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FieldType {
+    String,
+    ConcealedString,
+    Email,
+    Number,
+    Boolean,
+    Date,
+    YearMonth,
+    WifiNetworkSecurityType,
+    CountryCode,
+    SubdivisionCode,
+}
+
+/// 条目中的凭证，按`type`字段区分具体类型
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum TypedCredential {
+    BasicAuth(BasicAuthCredential),
+    Passkey(PasskeyCredential),
+    Totp(TotpCredential),
+    Note(NoteCredential),
+    CreditCard(CreditCardCredential),
+    ApiKey(ApiKeyCredential),
+    SshKey(SshKeyCredential),
+    Wifi(WifiCredential),
+    Address(AddressCredential),
+}
+
+impl TypedCredential {
+    pub fn credential_type(&self) -> CredentialType {
+        match self {
+            TypedCredential::BasicAuth(_) => CredentialType::BasicAuth,
+            TypedCredential::Passkey(_) => CredentialType::Passkey,
+            TypedCredential::Totp(_) => CredentialType::Totp,
+            TypedCredential::Note(_) => CredentialType::Note,
+            TypedCredential::CreditCard(_) => CredentialType::CreditCard,
+            TypedCredential::ApiKey(_) => CredentialType::ApiKey,
+            TypedCredential::SshKey(_) => CredentialType::SshKey,
+            TypedCredential::Wifi(_) => CredentialType::Wifi,
+            TypedCredential::Address(_) => CredentialType::Address,
+        }
+    }
+}
+
+/// 凭证类型，名称与[`TypedCredential`]的`type`字段一致
+/// 无法识别的类型名（如更新版本的CXF中新增的类型）解析为`Unknown`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum CredentialType {
+    BasicAuth,
+    Passkey,
+    Totp,
+    Note,
+    CreditCard,
+    ApiKey,
+    SshKey,
+    Wifi,
+    Address,
+    #[serde(other)]
+    Unknown,
+}
+
+impl CredentialType {
+    pub const ALL: &'static [CredentialType] = &[
+        CredentialType::BasicAuth,
+        CredentialType::Passkey,
+        CredentialType::Totp,
+        CredentialType::Note,
+        CredentialType::CreditCard,
+        CredentialType::ApiKey,
+        CredentialType::SshKey,
+        CredentialType::Wifi,
+        CredentialType::Address,
+    ];
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BasicAuthCredential {
+    pub username: Option<EditableField>,
+    pub password: Option<EditableField>,
+}
+
+/// 所有二进制字段均为Base64url编码，`key`为PKCS#8格式的私钥
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyCredential {
+    pub credential_id: String,
+    pub rp_id: String,
+    pub username: String,
+    pub user_display_name: String,
+    pub user_handle: String,
+    pub key: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TotpCredential {
+    pub secret: String,
+    pub period: u32,
+    pub digits: u32,
+    pub username: Option<String>,
+    pub algorithm: OtpHashAlgorithm,
+    pub issuer: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OtpHashAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NoteCredential {
+    pub content: EditableField,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CreditCardCredential {
+    pub number: Option<EditableField>,
+    pub full_name: Option<EditableField>,
+    pub card_type: Option<EditableField>,
+    pub verification_number: Option<EditableField>,
+    pub pin: Option<EditableField>,
+    pub expiry_date: Option<EditableField>,
+    pub valid_from: Option<EditableField>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyCredential {
+    pub key: Option<EditableField>,
+    pub username: Option<EditableField>,
+    pub key_type: Option<EditableField>,
+    pub url: Option<EditableField>,
+    pub valid_from: Option<EditableField>,
+    pub expiry_date: Option<EditableField>,
+}
+
+/// `private_key`为Base64url编码的PKCS#8私钥
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SshKeyCredential {
+    pub key_type: String,
+    pub private_key: String,
+    pub key_comment: Option<String>,
+    pub creation_date: Option<EditableField>,
+    pub expiry_date: Option<EditableField>,
+    pub key_generation_source: Option<EditableField>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WifiCredential {
+    pub ssid: Option<EditableField>,
+    pub network_security_type: Option<EditableField>,
+    pub passphrase: Option<EditableField>,
+    pub hidden: Option<EditableField>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AddressCredential {
+    pub street_address: Option<EditableField>,
+    pub postal_code: Option<EditableField>,
+    pub city: Option<EditableField>,
+    pub territory: Option<EditableField>,
+    pub country: Option<EditableField>,
+    pub tel: Option<EditableField>,
+}
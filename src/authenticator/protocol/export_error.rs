@@ -0,0 +1,79 @@
+//! # 导出错误
+//! 导出方无法处理请求时返回给导入方的消息，以错误码说明失败原因，
+//! 导入方据此给出类型化的错误，而不是把它当作格式错误的响应
+use crate::authenticator::error::AuthenticatorError as AuthError;
+use crate::authenticator::protocol::version::PROTOCOL_VERSION;
+use serde::{Deserialize, Serialize};
+
+/// 与`AuthenticatorError`对应的错误码，导出方内部的错误统一为`internal-error`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    RequestNotAllowed,
+    UnsupportedAlgorithm,
+    CredentialNotFound,
+    UnsupportedVersion,
+    InvalidRequest,
+    InvalidPublicKey,
+    UnknownRequest,
+    RequestExpired,
+    RequestConsumed,
+    UnknownPsk,
+    CryptoError,
+    InternalError,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportError {
+    pub version: u16,
+    /// 所对应请求的nonce，请求无法解析时为空
+    pub nonce: Option<String>,
+    pub error: ErrorCode,
+    pub message: String,
+}
+
+impl ExportError {
+    pub fn new(nonce: Option<String>, error: &AuthError) -> Self {
+        let code = ErrorCode::from(error);
+        // 内部错误的细节只留在导出方
+        let message = match code {
+            ErrorCode::InternalError => "导出方内部错误".to_string(),
+            _ => error.to_string(),
+        };
+        Self {
+            version: PROTOCOL_VERSION,
+            nonce,
+            error: code,
+            message,
+        }
+    }
+}
+
+impl From<&AuthError> for ErrorCode {
+    fn from(error: &AuthError) -> Self {
+        match error {
+            AuthError::RequestNotAllowed(_) => ErrorCode::RequestNotAllowed,
+            AuthError::UnsupportedAlgorithm | AuthError::KeyNotFound(_) => {
+                ErrorCode::UnsupportedAlgorithm
+            }
+            AuthError::CredentialNotFound => ErrorCode::CredentialNotFound,
+            AuthError::UnsupportedVersion(_) => ErrorCode::UnsupportedVersion,
+            AuthError::InvalidRequest(_) | AuthError::CodeError(_) => ErrorCode::InvalidRequest,
+            AuthError::InvalidPublicKey(_) => ErrorCode::InvalidPublicKey,
+            AuthError::UnknownRequest => ErrorCode::UnknownRequest,
+            AuthError::RequestExpired => ErrorCode::RequestExpired,
+            AuthError::RequestConsumed => ErrorCode::RequestConsumed,
+            AuthError::UnknownPsk(_) => ErrorCode::UnknownPsk,
+            AuthError::CryptoError(_) => ErrorCode::CryptoError,
+            AuthError::ExportFailed(code, _) => *code,
+            AuthError::InnerAuthenticatorError(_)
+            | AuthError::InternalError(_)
+            | AuthError::IncorrectPin
+            | AuthError::PairingFailed(_)
+            | AuthError::SasNotConfirmed
+            | AuthError::InvalidReceipt(_)
+            | AuthError::InvalidSasReveal
+            | AuthError::AuditChainBroken(_) => ErrorCode::InternalError,
+        }
+    }
+}
@@ -9,6 +9,8 @@ pub struct HPKEParameters {
     pub kdf: u16,
     pub aead: u16,
     pub key: JWKS, //JWK as JSON string
+    /// Psk和AuthPsk模式下使用的预共享密钥标识
+    pub psk_id: Option<String>,
 }
 
 pub struct JWK {
@@ -18,7 +20,9 @@ pub struct JWK {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct JWKS {
+    /// 响应中的封装密钥
     pub enc: Option<String>,
+    /// 请求中为导入方的接收公钥；Auth和AuthPsk模式的响应中为导出方的身份公钥，两者互不相同
     pub pk: Option<String>,
 }
 
@@ -28,17 +32,28 @@ impl PartialEq for HPKEParameters {
             && self.kdf == other.kdf
             && self.aead == other.aead
             && self.kem == other.kem
+            && self.psk_id == other.psk_id
     }
 }
 
 impl HPKEParameters {
+    /// 算法套件相同，不比较psk_id
+    pub fn same_suite(&self, other: &Self) -> bool {
+        self.mode == other.mode
+            && self.kdf == other.kdf
+            && self.aead == other.aead
+            && self.kem == other.kem
+    }
+    pub fn uses_psk(&self) -> bool {
+        matches!(self.mode, HPKEMode::Psk | HPKEMode::AuthPsk)
+    }
     pub fn decode_jwk(&self) -> Result<JWK, AuthErr> {
         let decode = |s: &Option<String>| -> Result<Option<Vec<u8>>, AuthErr> {
             s.as_ref()
                 .map(|s| {
                     BASE64_URL_SAFE
                         .decode(s)
-                        .map_err(|e| AuthErr::CodeError(format!("Decode error:{}", e.to_string())))
+                        .map_err(|e| AuthErr::CodeError(format!("Decode error:{}", e)))
                 })
                 .transpose()
         };
@@ -66,3 +81,15 @@ pub enum HPKEMode {
     Auth,
     AuthPsk,
 }
+
+impl HPKEMode {
+    /// RFC 9180中规定的模式标识
+    pub fn id(&self) -> u8 {
+        match self {
+            HPKEMode::Base => 0,
+            HPKEMode::Psk => 1,
+            HPKEMode::Auth => 2,
+            HPKEMode::AuthPsk => 3,
+        }
+    }
+}
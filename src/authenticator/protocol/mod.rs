@@ -1,5 +1,10 @@
+pub mod archive;
 pub mod credential;
+pub mod cxf;
+pub mod export_error;
+pub mod hpke_format;
+pub mod receipt;
 pub mod request;
 pub mod response;
-pub mod hpke_format;
-pub mod archive;
+pub mod sas;
+pub mod version;
@@ -0,0 +1,85 @@
+//! # 导入回执
+//! 导入方处理响应后向导出方返回回执，说明导入结果和已导入条目的摘要。
+//! 回执以从同一次HPKE交换中导出的密钥计算HMAC，只有完成该次交换的导入方才能生成，
+//! 导出方验证后可以将已导出的凭证标记为已迁移或删除
+use crate::authenticator::error::AuthenticatorError as AuthError;
+use crate::authenticator::protocol::cxf::Item;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// 从HPKE上下文导出回执密钥所用的上下文和长度
+pub const RECEIPT_CONTEXT: &[u8] = b"fido-cx import receipt";
+pub const RECEIPT_KEY_LEN: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReceiptStatus {
+    /// 凭证已存储到导入方
+    Imported,
+    /// 导入方放弃了导入，如短认证码不一致
+    Rejected,
+}
+
+/// 导出方验证回执后对已导入凭证的处理方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReceiptAction {
+    Keep,
+    /// 标记为已迁移，凭证仍保留
+    Mark,
+    Delete,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportReceipt {
+    pub version: u16,
+    /// 所对应请求的nonce
+    pub nonce: String,
+    pub status: ReceiptStatus,
+    pub count: usize,
+    /// 已导入条目的摘要，Base64url编码
+    pub items: Vec<String>,
+    /// 以回执密钥计算的HMAC-SHA256，Base64url编码
+    pub mac: String,
+}
+
+impl ImportReceipt {
+    /// 条目的摘要，导出方和导入方对各自看到的条目计算
+    pub fn item_hash(item: &Item) -> Result<String, AuthError> {
+        let digest = Sha256::digest(serde_json::to_vec(item)?);
+        Ok(BASE64_URL_SAFE_NO_PAD.encode(digest))
+    }
+
+    /// 计算HMAC，覆盖除`mac`以外的所有字段
+    pub fn sign(&mut self, key: &[u8]) -> Result<(), AuthError> {
+        let mac = Self::mac(key)?
+            .chain_update(self.mac_input()?)
+            .finalize()
+            .into_bytes();
+        self.mac = BASE64_URL_SAFE_NO_PAD.encode(mac);
+        Ok(())
+    }
+
+    /// 以常数时间校验HMAC
+    pub fn verify(&self, key: &[u8]) -> Result<(), AuthError> {
+        let mac = BASE64_URL_SAFE_NO_PAD
+            .decode(&self.mac)
+            .map_err(|_| AuthError::InvalidReceipt("HMAC编码错误".to_string()))?;
+        Self::mac(key)?
+            .chain_update(self.mac_input()?)
+            .verify_slice(&mac)
+            .map_err(|_| AuthError::InvalidReceipt("HMAC校验失败".to_string()))
+    }
+
+    fn mac(key: &[u8]) -> Result<Hmac<Sha256>, AuthError> {
+        Hmac::<Sha256>::new_from_slice(key).map_err(|e| AuthError::CryptoError(e.to_string()))
+    }
+
+    fn mac_input(&self) -> Result<Vec<u8>, AuthError> {
+        let mut receipt = self.clone();
+        receipt.mac.clear();
+        Ok(serde_json::to_vec(&receipt)?)
+    }
+}
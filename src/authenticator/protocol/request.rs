@@ -1,18 +1,47 @@
+use crate::authenticator::error::AuthenticatorError as AuthError;
 use crate::authenticator::protocol::archive::ArchiveAlgorithm;
+use crate::authenticator::protocol::cxf::CredentialType;
 use crate::authenticator::protocol::hpke_format::HPKEParameters;
+use crate::authenticator::protocol::version::{PROTOCOL_VERSION, SUPPORTED_VERSIONS};
+use crate::authenticator::unix_time;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// 请求摘要和HPKE info的域分隔标签
+const TRANSCRIPT_LABEL: &[u8] = b"fido-cx export request";
+const INFO_LABEL: &[u8] = b"fido-cx export info";
+/// 请求的有效期，单位为秒
+pub const REQUEST_LIFETIME: u64 = 10 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ExportRequest {
     pub version: u16,
+    /// 导入方支持的所有协议版本，导出方不支持`version`时据此降级
+    pub supported_versions: Option<Vec<u16>>,
     pub hpke_parameters: Vec<HPKEParameters>,
     pub archive: Vec<ArchiveAlgorithm>,
     pub mode: ResponseMode,
     pub importer: String,
-    pub credential_types: Option<Vec<String>>,
+    /// 导入方生成的随机数，Base64url编码，响应中原样返回以对应到请求
+    pub nonce: String,
+    /// 请求的签发和过期时间，Unix时间戳，单位为秒
+    pub issued_at: u64,
+    pub expires_at: u64,
+    /// 导入方接受的凭证类型，为`None`时接受所有类型，列表不能为空
+    pub credential_types: Option<Vec<CredentialType>>,
     pub known_extensions: Option<Vec<String>>,
+    /// 各扩展随请求附带的数据，以扩展名为键
+    pub extensions: Option<Map<String, Value>>,
+    /// 间接响应模式下导出方投递响应的中继信箱
+    pub relay: Option<String>,
+    /// 导入方对短认证码随机数的承诺，Base64url编码，自备份请求没有该字段
+    pub sas_commitment: Option<String>,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub enum ResponseMode {
     Direct,
@@ -27,19 +56,56 @@ impl ExportRequest {
         mode: ResponseMode,
         importer: String,
         archive: Vec<ArchiveAlgorithm>,
-        credential_types: Option<Vec<String>>,
+        credential_types: Option<Vec<CredentialType>>,
         known_extensions: Option<Vec<String>>,
     ) -> Self {
+        let issued_at = unix_time();
         Self {
-            version: 0,
+            version: PROTOCOL_VERSION,
+            supported_versions: Some(SUPPORTED_VERSIONS.to_vec()),
             hpke_parameters,
             archive,
             mode,
             importer,
+            nonce: new_nonce(),
+            issued_at,
+            expires_at: issued_at + REQUEST_LIFETIME,
             credential_types,
             known_extensions,
+            extensions: None,
+            relay: None,
+            sas_commitment: None,
+        }
+    }
+}
+
+impl ExportRequest {
+    /// 请求的规范摘要，覆盖除各套件封装密钥以外的所有字段
+    pub fn transcript_hash(&self) -> Result<Vec<u8>, AuthError> {
+        let mut request = self.clone();
+        for param in &mut request.hpke_parameters {
+            param.key.enc = None;
         }
+        let mut hasher = Sha256::new();
+        hasher.update(TRANSCRIPT_LABEL);
+        hasher.update(serde_json::to_vec(&request)?);
+        Ok(hasher.finalize().to_vec())
     }
+
+    /// 绑定到HPKE上下文的info
+    pub fn hpke_info(&self) -> Result<Vec<u8>, AuthError> {
+        Ok([INFO_LABEL, &self.transcript_hash()?].concat())
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        now > self.expires_at
+    }
+}
+
+fn new_nonce() -> String {
+    let mut nonce = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    BASE64_URL_SAFE_NO_PAD.encode(nonce)
 }
 
 impl From<ExportRequest> for String {
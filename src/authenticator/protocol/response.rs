@@ -1,5 +1,8 @@
+use crate::authenticator::error::AuthenticatorError as AuthError;
 use crate::authenticator::protocol::archive::ArchiveAlgorithm;
+use crate::authenticator::protocol::cxf::CredentialType;
 use crate::authenticator::protocol::hpke_format::HPKEParameters;
+use crate::authenticator::protocol::request::ExportRequest;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -8,5 +11,31 @@ pub struct ExportResponse {
     pub hpke_parameters: HPKEParameters,
     pub archive: ArchiveAlgorithm,
     pub exporter: String,
-    pub payload: String, // Base64url encoded data
+    /// 所响应请求的nonce和有效期，作为AAD的一部分受到认证
+    pub nonce: String,
+    pub issued_at: u64,
+    pub expires_at: u64,
+    /// 按顺序排列的密文记录，每条均为Base64url编码
+    pub payload: Vec<String>,
+    /// 导出方因导入方不接受而跳过的凭证类型
+    pub skipped_credential_types: Option<Vec<CredentialType>>,
+    /// 导出方实际应用的扩展
+    pub extensions: Option<Vec<String>>,
+}
+
+impl ExportResponse {
+    /// 绑定到HPKE上下文的AAD，覆盖除密文和封装密钥以外的所有明文字段
+    pub fn aad(&self) -> Result<Vec<u8>, AuthError> {
+        let mut header = self.clone();
+        header.payload.clear();
+        header.hpke_parameters.key.enc = None;
+        Ok(serde_json::to_vec(&header)?)
+    }
+}
+
+/// 自备份文件，保存请求以便恢复时重新计算info和AAD
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backup {
+    pub request: ExportRequest,
+    pub response: ExportResponse,
 }
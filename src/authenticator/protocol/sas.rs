@@ -0,0 +1,44 @@
+//! # 短认证码的承诺与揭示
+//! 导入方在请求中承诺一个随机数，收到响应后才将其揭示给导出方，双方的短认证码同时覆盖HPKE导出的密钥和该随机数。
+//! 中间人确定发给导入方的封装密钥时还不知道随机数，无法离线反复尝试使双方的认证码相同
+use crate::authenticator::error::AuthenticatorError as AuthError;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const COMMITMENT_LABEL: &[u8] = b"fido-cx sas commitment";
+/// 承诺值解码后的字节长度
+pub const COMMITMENT_LEN: usize = 32;
+
+/// 导入方处理响应后发送给导出方的揭示消息
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SasReveal {
+    pub version: u16,
+    /// 所对应请求的nonce
+    pub nonce: String,
+    /// 请求中承诺的随机数，Base64url编码
+    pub reveal: String,
+}
+
+impl SasReveal {
+    /// 随机数的承诺值，Base64url编码
+    pub fn commitment(reveal: &[u8]) -> String {
+        let digest = Sha256::new()
+            .chain_update(COMMITMENT_LABEL)
+            .chain_update(reveal)
+            .finalize();
+        BASE64_URL_SAFE_NO_PAD.encode(digest)
+    }
+
+    /// 揭示的随机数与承诺一致时返回该随机数
+    pub fn open(&self, commitment: &str) -> Result<Vec<u8>, AuthError> {
+        let reveal = BASE64_URL_SAFE_NO_PAD
+            .decode(&self.reveal)
+            .map_err(|_| AuthError::InvalidSasReveal)?;
+        if Self::commitment(&reveal) != commitment {
+            return Err(AuthError::InvalidSasReveal);
+        }
+        Ok(reveal)
+    }
+}
@@ -0,0 +1,86 @@
+//! # 协议版本
+//! 请求和响应先读取`version`字段并检查是否支持，再按对应版本的格式解析，避免将新格式的消息按旧格式误解析
+//!
+//! 导入方在请求中列出自身支持的所有版本，导出方不支持请求的版本时降级到双方都支持的最高版本
+use crate::authenticator::error::AuthenticatorError as AuthError;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+/// 构造请求和响应时使用的版本
+pub const PROTOCOL_VERSION: u16 = 0;
+/// 本实现能够解析的版本
+pub const SUPPORTED_VERSIONS: &[u16] = &[0];
+
+/// 读取消息的版本号
+pub fn peek_version(message: &str) -> Result<(u16, Value), AuthError> {
+    let value: Value = serde_json::from_str(message)?;
+    Ok((version_of(&value)?, value))
+}
+
+fn version_of(value: &Value) -> Result<u16, AuthError> {
+    value
+        .get("version")
+        .and_then(Value::as_u64)
+        .and_then(|v| u16::try_from(v).ok())
+        .ok_or(AuthError::CodeError("消息缺少有效的版本号".to_string()))
+}
+
+/// 确定响应使用的版本：支持请求的版本时直接使用，否则选择`advertised`中自身也支持的最高版本
+pub fn negotiate(requested: u16, advertised: Option<&[u16]>) -> Result<u16, AuthError> {
+    if SUPPORTED_VERSIONS.contains(&requested) {
+        return Ok(requested);
+    }
+    advertised
+        .unwrap_or_default()
+        .iter()
+        .filter(|v| SUPPORTED_VERSIONS.contains(v))
+        .max()
+        .copied()
+        .ok_or(AuthError::UnsupportedVersion(requested))
+}
+
+/// 解析请求，返回请求和响应应使用的版本，格式错误的请求返回[`AuthError::InvalidRequest`]
+pub fn decode_request<T: DeserializeOwned>(message: &str) -> Result<(T, u16), AuthError> {
+    let value: Value = serde_json::from_str(message).map_err(invalid_request)?;
+    let version = version_of(&value)?;
+    let advertised: Option<Vec<u16>> = match value.get("supported_versions") {
+        Some(versions) => serde_json::from_value(versions.clone()).map_err(invalid_request)?,
+        None => None,
+    };
+    let version = negotiate(version, advertised.as_deref())?;
+    Ok((
+        serde_json::from_value(value).map_err(invalid_request)?,
+        version,
+    ))
+}
+
+fn invalid_request(error: serde_json::Error) -> AuthError {
+    AuthError::InvalidRequest(error.to_string())
+}
+
+/// 解析响应，只接受本实现支持的版本
+/// 响应是否使用请求中声明的版本由[`check_requested`]对照发出的请求检查
+pub fn decode_response<T: DeserializeOwned>(message: &str) -> Result<T, AuthError> {
+    let (version, value) = peek_version(message)?;
+    check_version(version)?;
+    serde_json::from_value(value).map_err(Into::into)
+}
+
+/// 响应的版本必须是请求的版本或请求中列出的支持版本之一
+pub fn check_requested(
+    version: u16,
+    requested: u16,
+    advertised: Option<&[u16]>,
+) -> Result<(), AuthError> {
+    if version != requested && !advertised.unwrap_or_default().contains(&version) {
+        return Err(AuthError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+pub fn check_version(version: u16) -> Result<(), AuthError> {
+    if !SUPPORTED_VERSIONS.contains(&version) {
+        return Err(AuthError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
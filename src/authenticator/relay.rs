@@ -0,0 +1,78 @@
+//! # 中继
+//! 间接响应模式下，导出方将加密后的导出响应投递到请求中指定的信箱，导入方之后从中继取回
+//!
+//! 中继只接触序列化后的[`ExportResponse`](crate::authenticator::protocol::response::ExportResponse)，
+//! 其中的凭证数据已由HPKE加密，中继无法得到明文
+use crate::authenticator::error::AuthenticatorError as AuthError;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// 未指定时使用的中继目录
+pub const DEFAULT_RELAY_DIR: &str = "relay";
+
+pub trait Relay {
+    /// 将响应投递到信箱
+    fn post(&self, mailbox: &str, message: &str) -> Result<(), AuthError>;
+    /// 取回并移除信箱中的响应，尚未投递时返回`None`
+    fn fetch(&self, mailbox: &str) -> Result<Option<String>, AuthError>;
+}
+
+/// 导出请求的处理结果
+#[derive(Debug)]
+pub enum Delivery {
+    /// 直接返回给导入方的响应
+    Direct(String),
+    /// 已投递到中继的信箱
+    Relayed(String),
+}
+
+/// 以本地目录作为中继，每个信箱对应目录中的一个文件，可用于测试或通过共享目录交换
+pub struct DirectoryRelay {
+    pub root: PathBuf,
+}
+
+impl DirectoryRelay {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// 信箱名来自请求，只允许字母、数字、`-`和`_`，防止写出中继目录
+    fn mailbox_path(&self, mailbox: &str) -> Result<PathBuf, AuthError> {
+        let valid = !mailbox.is_empty()
+            && mailbox
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(AuthError::RequestNotAllowed(format!(
+                "非法的中继信箱：{}",
+                mailbox
+            )));
+        }
+        Ok(self.root.join(format!("{}.json", mailbox)))
+    }
+}
+
+impl Relay for DirectoryRelay {
+    fn post(&self, mailbox: &str, message: &str) -> Result<(), AuthError> {
+        let path = self.mailbox_path(mailbox)?;
+        fs::create_dir_all(&self.root)?;
+        // 先写入临时文件再重命名，避免导入方读到不完整的响应
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, message)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    fn fetch(&self, mailbox: &str) -> Result<Option<String>, AuthError> {
+        let path = self.mailbox_path(mailbox)?;
+        match fs::read_to_string(&path) {
+            Ok(message) => {
+                fs::remove_file(path)?;
+                Ok(Some(message))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
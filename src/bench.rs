@@ -1,7 +1,8 @@
+use crate::authenticator::pin::PinInner;
 use crate::authenticator::protocol::hpke_format::HPKEMode::{Auth, AuthPsk, Base, Psk};
-use std::time::{Duration, Instant};
 use crate::authenticator::Authenticator;
-use crate::authenticator::pin::PinInner;
+use crate::test::share_psk;
+use std::time::{Duration, Instant};
 
 // 常量定义
 const TEST_ITERATIONS: usize = 5;
@@ -24,12 +25,9 @@ fn time_test() {
 
             kdf_id.into_iter().for_each(|kdf| {
                 aead_id.into_iter().for_each(|aead| {
-                    let importer = Authenticator {
-                        inner: PinInner::new(kem, kdf, aead, &mode),
-                    };
-                    let exporter = Authenticator {
-                        inner: PinInner::new(kem, kdf, aead, &mode),
-                    };
+                    let importer = Authenticator::new(PinInner::new(kem, kdf, aead, &mode));
+                    let exporter = Authenticator::new(PinInner::new(kem, kdf, aead, &mode));
+                    share_psk(&importer, &exporter);
 
                     let start = Instant::now();
                     for _ in 0..TEST_ITERATIONS {
@@ -1,19 +1,36 @@
+use crate::authenticator::audit::AuditOutcome;
+use crate::authenticator::consent::{Consent, ConsentProvider, ConsentRequest};
+use crate::authenticator::error::AuthenticatorError as AuthError;
 use crate::authenticator::inner::InnerAuthenticator;
+use crate::authenticator::keystore::DEFAULT_KEYSTORE_PATH;
 use crate::authenticator::pin::PinInner;
-use crate::authenticator::Authenticator;
+use crate::authenticator::policy::{Policy, DEFAULT_POLICY_PATH};
+use crate::authenticator::protocol::receipt::{ImportReceipt, ReceiptAction, ReceiptStatus};
+use crate::authenticator::relay::{Delivery, DirectoryRelay, DEFAULT_RELAY_DIR};
+use crate::authenticator::{Authenticator, ImportResult};
 use authenticator::protocol::credential::Credential;
-use std::time::{Duration, Instant};
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
 
-use crate::authenticator::protocol::hpke_format::HPKEMode::{Auth, AuthPsk, Base, Psk};
 use colored::*;
-use dialoguer::{Input, Select};
+use dialoguer::{Confirm, Input, MultiSelect, Password, Select};
 
 pub mod authenticator;
 #[cfg(test)]
-mod test;
 mod bench;
+#[cfg(test)]
+mod test;
 
 use std::fs;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::Duration;
+
+const MAX_PIN_ATTEMPTS: usize = 3;
+// 从中继取回响应时的轮询次数和间隔
+const RELAY_POLL_ATTEMPTS: usize = 10;
+const RELAY_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 pub fn export_file(file_path: &str, content: String) -> Result<(), String> {
     fs::write(file_path, content).map_err(|e| e.to_string())
@@ -41,18 +58,78 @@ fn list_creds<T: InnerAuthenticator>(a: &Authenticator<T>) {
     };
 }
 
+// 导出前列出导入方和候选凭证，由用户勾选要导出的凭证
+struct CliConsent;
+
+impl ConsentProvider for CliConsent {
+    fn consent(&self, request: &ConsentRequest) -> Consent {
+        println!(
+            "导入方公钥指纹：{}",
+            request.importer.key_fingerprint.bold()
+        );
+        if let Some(psk_id) = &request.importer.psk_id {
+            println!("已配对设备：{}", psk_id);
+        }
+        println!("请求的RP：{}", request.rp_id);
+        if let Some(types) = request.credential_types {
+            println!("接受的凭证类型：{:?}", types);
+        }
+        let items: Vec<String> = request
+            .candidates
+            .iter()
+            .map(|item| format!("{} : {}", item.title, item.id))
+            .collect();
+        let selected = MultiSelect::new()
+            .with_prompt("选择要导出的凭证（空格选择，回车确认）")
+            .items(&items)
+            .defaults(&vec![true; items.len()])
+            .interact()
+            .unwrap_or_default();
+        if selected.is_empty() {
+            Consent::Deny
+        } else if selected.len() == items.len() {
+            Consent::Approve
+        } else {
+            Consent::Subset(
+                selected
+                    .into_iter()
+                    .map(|i| request.candidates[i].id.clone())
+                    .collect(),
+            )
+        }
+    }
+}
+
+// 中继目录可以通过环境变量FIDO_CX_RELAY指定
+fn relay() -> DirectoryRelay {
+    let root = std::env::var("FIDO_CX_RELAY").unwrap_or_else(|_| DEFAULT_RELAY_DIR.to_string());
+    DirectoryRelay::new(root)
+}
+
 fn request<T: InnerAuthenticator>(a: &Authenticator<T>) {
     let name: String = Input::new()
         .with_prompt("输入导入凭证的RPID:")
         .interact_text()
         .unwrap();
-    let export_request = a
-        .construct_export_request(name.to_string())
-        .expect("Construct Error");
+    let mode = Select::new()
+        .with_prompt("响应方式")
+        .items(&["  直接响应", "  通过中继响应"])
+        .interact()
+        .unwrap();
+    let export_request = if mode == 0 {
+        a.construct_export_request(name.to_string())
+    } else {
+        let mut mailbox = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut mailbox);
+        let mailbox = BASE64_URL_SAFE_NO_PAD.encode(mailbox);
+        println!("中继信箱：{}", mailbox);
+        a.construct_indirect_request(name.to_string(), mailbox)
+    }
+    .expect("Construct Error");
     if let Err(e) = export_file("request.json", export_request) {
         println!("{}", ColoredString::from(e).red().bold());
     } else {
-        println!("{}", "请求已导出到request.json中");
+        println!("请求已导出到request.json中");
     }
 }
 fn export<T: InnerAuthenticator>(a: &Authenticator<T>) {
@@ -61,37 +138,339 @@ fn export<T: InnerAuthenticator>(a: &Authenticator<T>) {
         .interact_text()
         .unwrap();
 
-    let export = || -> Result<(), String> {
-        let export_request = import_from_file(&name.to_string())?;
-        let res = a
-            .handle_request(export_request)
-            .map_err(|e| e.to_string())?;
-        export_file("response.json", res)
+    let export_request = match import_from_file(&name.to_string()) {
+        Ok(request) => request,
+        Err(e) => {
+            println!("导出错误：{}", ColoredString::from(e).red().bold());
+            return;
+        }
     };
-    if let Err(e) = export() {
-        println!("导出错误：{}", ColoredString::from(e).red().bold());
-    } else {
-        println!("凭证已导出到response.json");
+    let delivered = |delivery: &Delivery| -> Result<(), String> {
+        if let Delivery::Direct(res) = delivery {
+            export_file("response.json", res.clone())?;
+        }
+        Ok(())
+    };
+    match a.respond(export_request.clone(), &relay()) {
+        Ok(delivery) => {
+            if let Err(e) = delivered(&delivery) {
+                println!("导出错误：{}", ColoredString::from(e).red().bold());
+                return;
+            }
+            match delivery {
+                Delivery::Direct(_) => println!("凭证已导出到response.json"),
+                Delivery::Relayed(mailbox) => println!("凭证已投递到中继信箱{}", mailbox),
+            }
+            show_sas(a);
+        }
+        // 将失败原因告知导入方
+        Err(e) => {
+            println!(
+                "导出错误：{}",
+                ColoredString::from(e.to_string()).red().bold()
+            );
+            let rejected = a
+                .reject_request(&export_request, &e, &relay())
+                .map_err(|e| e.to_string())
+                .and_then(|delivery| delivered(&delivery).map(|_| delivery));
+            match rejected {
+                Ok(Delivery::Direct(_)) => println!("错误消息已写入response.json"),
+                Ok(Delivery::Relayed(mailbox)) => println!("错误消息已投递到中继信箱{}", mailbox),
+                Err(e) => println!("{}", ColoredString::from(e).red().bold()),
+            }
+        }
+    }
+}
+// 导入方揭示随机数后才能计算短认证码
+fn show_sas<T: InnerAuthenticator>(a: &Authenticator<T>) {
+    let name: String = Input::new()
+        .with_prompt("输入导入方的揭示文件路径")
+        .interact_text()
+        .unwrap();
+    let sas = import_from_file(&name)
+        .and_then(|reveal| a.verify_sas_reveal(reveal).map_err(|e| e.to_string()));
+    match sas {
+        Ok(sas) => println!("短认证码：{}，请与导入方显示的认证码核对", sas.bold()),
+        Err(e) => println!("短认证码错误：{}", ColoredString::from(e).red().bold()),
     }
 }
 fn import<T: InnerAuthenticator>(a: &Authenticator<T>) {
+    let source = Select::new()
+        .with_prompt("响应来源")
+        .items(&["  响应文件", "  中继信箱"])
+        .interact()
+        .unwrap();
+    let result = if source == 0 {
+        import_file(a)
+    } else {
+        import_relay(a)
+    };
+    match result {
+        Ok(mut import) => {
+            println!("收到来自{}的凭证：", import.header.exporter_display_name);
+            import
+                .header
+                .items()
+                .for_each(|item| println!("  {} : {}", item.title, item.id));
+            if !import.skipped_types.is_empty() {
+                println!("导出方跳过的凭证类型：{:?}", import.skipped_types);
+            }
+            let revealed = a
+                .sas_reveal(&import)
+                .map_err(|e| e.to_string())
+                .and_then(|reveal| export_file("reveal.json", reveal));
+            if let Err(e) = revealed {
+                println!("揭示错误：{}", ColoredString::from(e).red().bold());
+                return;
+            }
+            println!("揭示消息已保存到reveal.json，请发送给导出方");
+            println!("短认证码：{}", import.sas.bold());
+            let confirmed = Confirm::new()
+                .with_prompt("导出方显示的短认证码是否一致")
+                .default(false)
+                .interact()
+                .unwrap();
+            let status = if confirmed {
+                import.confirm();
+                match a.commit_import(&import) {
+                    Ok(count) => println!("已存储{}个凭证", count),
+                    Err(e) => {
+                        println!(
+                            "存储错误：{}",
+                            ColoredString::from(e.to_string()).red().bold()
+                        );
+                        return;
+                    }
+                }
+                ReceiptStatus::Imported
+            } else {
+                println!("{}", "短认证码不一致，已放弃导入".red().bold());
+                ReceiptStatus::Rejected
+            };
+            let receipt = a
+                .import_receipt(&import, status)
+                .map_err(|e| e.to_string())
+                .and_then(|receipt| export_file("receipt.json", receipt));
+            match receipt {
+                Ok(()) => println!("导入回执已保存到receipt.json，请发送给导出方"),
+                Err(e) => println!("回执错误：{}", ColoredString::from(e).red().bold()),
+            }
+        }
+        Err(e) => println!("导入错误：{}", ColoredString::from(e).red().bold()),
+    }
+}
+fn import_file<T: InnerAuthenticator>(a: &Authenticator<T>) -> Result<ImportResult, String> {
     let name: String = Input::new()
         .with_prompt("输入导出相应文件路径")
         .interact_text()
         .unwrap();
+    let export = import_from_file(&name.to_string())?;
+    a.handle_response(export).map_err(|e| e.to_string())
+}
+// 轮询中继信箱，直到导出方投递响应
+fn import_relay<T: InnerAuthenticator>(a: &Authenticator<T>) -> Result<ImportResult, String> {
+    let mailbox: String = Input::new()
+        .with_prompt("输入中继信箱")
+        .interact_text()
+        .unwrap();
+    let relay = relay();
+    for _ in 0..RELAY_POLL_ATTEMPTS {
+        let fetched = a
+            .fetch_response(&mailbox, &relay)
+            .map_err(|e| e.to_string())?;
+        if let Some(import) = fetched {
+            return Ok(import);
+        }
+        println!("等待导出方响应...");
+        sleep(RELAY_POLL_INTERVAL);
+    }
+    Err("中继信箱中没有响应".to_string())
+}
+// 验证导入方返回的回执，并选择如何处理已导出的凭证
+fn receipt<T: InnerAuthenticator>(a: &Authenticator<T>) {
+    let name: String = Input::new()
+        .with_prompt("输入导入回执文件路径")
+        .interact_text()
+        .unwrap();
+    let action = Select::new()
+        .with_prompt("导入成功后如何处理已导出的凭证")
+        .items(&["  保留", "  标记为已迁移", "  删除"])
+        .interact()
+        .unwrap();
+    let action = [
+        ReceiptAction::Keep,
+        ReceiptAction::Mark,
+        ReceiptAction::Delete,
+    ][action];
+    let verify = || -> Result<ImportReceipt, String> {
+        let receipt = import_from_file(&name.to_string())?;
+        a.verify_receipt(receipt, action).map_err(|e| e.to_string())
+    };
+    match verify() {
+        Ok(receipt) if receipt.status == ReceiptStatus::Imported => {
+            println!("导入方已导入{}个凭证", receipt.count)
+        }
+        Ok(_) => println!("导入方放弃了导入"),
+        Err(e) => println!("回执错误：{}", ColoredString::from(e).red().bold()),
+    }
+}
+// 列出审计日志并校验哈希链
+fn audit<T: InnerAuthenticator>(a: &Authenticator<T>) {
+    let log = a.inner.audit_log();
+    for entry in log.entries() {
+        let record = &entry.record;
+        let suite = match (&record.suite, record.kem) {
+            (Some(suite), _) => {
+                format!("{:#06x}/{:#06x}/{:#06x}", suite.kem, suite.kdf, suite.aead)
+            }
+            (None, Some(kem)) => format!("{:#06x}", kem),
+            (None, None) => "-".to_string(),
+        };
+        let outcome = match &record.outcome {
+            AuditOutcome::Success => "成功".green(),
+            AuditOutcome::Failure(e) => ColoredString::from(e.as_str()).red(),
+        };
+        println!(
+            "{} [{}] {:?} 对方：{} RP：{} 套件：{} {}",
+            entry.seq,
+            record.timestamp,
+            record.event,
+            record.peer.as_deref().unwrap_or("-"),
+            record.rp_ids.join(","),
+            suite,
+            outcome
+        );
+    }
+    match log.verify() {
+        Ok(()) => println!("审计日志共{}条记录，校验通过", log.entries().len()),
+        Err(e) => println!("{}", ColoredString::from(e.to_string()).red().bold()),
+    }
+}
+fn backup<T: InnerAuthenticator>(a: &Authenticator<T>) {
+    let backup = || -> Result<(), String> {
+        let backup = a.backup().map_err(|e| e.to_string())?;
+        export_file("backup.json", backup)
+    };
+    if let Err(e) = backup() {
+        println!("备份错误：{}", ColoredString::from(e).red().bold());
+    } else {
+        println!("凭证已备份到backup.json");
+    }
+}
+fn restore<T: InnerAuthenticator>(a: &Authenticator<T>) {
+    let name: String = Input::new()
+        .with_prompt("输入备份文件路径")
+        .interact_text()
+        .unwrap();
+
+    let restore = || -> Result<usize, String> {
+        let backup = import_from_file(&name.to_string())?;
+        a.restore(backup).map_err(|e| e.to_string())
+    };
+    match restore() {
+        Ok(count) => println!("已从备份恢复{}个凭证", count),
+        Err(e) => println!("恢复错误：{}", ColoredString::from(e).red().bold()),
+    }
+}
+// 两台设备输入相同的配对码，通过文件交换配对消息，协商出的PSK保存在密钥库中
+fn pair<T: InnerAuthenticator>(a: &Authenticator<T>) {
+    let role = Select::new()
+        .with_prompt("配对角色")
+        .items(&["  发起配对", "  响应配对"])
+        .interact()
+        .unwrap();
+    let code: String = Input::new()
+        .with_prompt("输入双方约定的配对码")
+        .interact_text()
+        .unwrap();
+    let read_message = |prompt: &str| -> Result<String, String> {
+        let name: String = Input::new().with_prompt(prompt).interact_text().unwrap();
+        import_from_file(&name)
+    };
 
-    let export = || -> Result<String, String> {
-        let export = import_from_file(&name.to_string())?;
-        a.handle_response(export).map_err(|e| e.to_string())
+    let pair = || -> Result<String, String> {
+        let psk_id = if role == 0 {
+            let (session, start) = a.start_pairing(&code).map_err(|e| e.to_string())?;
+            export_file("pair_start.json", start)?;
+            println!("配对消息已导出到pair_start.json，请发送给对方");
+            let response = read_message("输入对方的配对响应文件路径")?;
+            let (psk_id, confirm) = a
+                .finish_pairing(session, &response)
+                .map_err(|e| e.to_string())?;
+            if let Some(confirm) = confirm {
+                export_file("pair_confirm.json", confirm)?;
+                println!("确认消息已导出到pair_confirm.json，请发送给对方");
+            }
+            psk_id
+        } else {
+            let start = read_message("输入对方的配对发起文件路径")?;
+            let (session, response) = a
+                .respond_pairing(&code, &start)
+                .map_err(|e| e.to_string())?;
+            export_file("pair_response.json", response)?;
+            println!("配对响应已导出到pair_response.json，请发送给对方");
+            let confirm = read_message("输入对方的确认文件路径")?;
+            a.finish_pairing(session, &confirm)
+                .map_err(|e| e.to_string())?
+                .0
+        };
+        Ok(psk_id)
     };
-    if let Err(e) = export() {
-        println!("导入错误：{}", ColoredString::from(e).red().bold());
+    match pair() {
+        Ok(psk_id) => println!("配对成功，预共享密钥标识：{}", psk_id),
+        Err(e) => println!("配对错误：{}", ColoredString::from(e).red().bold()),
     }
 }
+// 输入PIN解锁密钥库，密钥库不存在时设置新的PIN
+fn unlock(keystore: &str) -> Option<PinInner> {
+    for _ in 0..MAX_PIN_ATTEMPTS {
+        let pin = if Path::new(keystore).exists() {
+            Password::new().with_prompt("输入PIN解锁密钥库")
+        } else {
+            Password::new()
+                .with_prompt("设置密钥库PIN")
+                .with_confirmation("再次输入PIN", "两次输入的PIN不一致")
+        }
+        .interact()
+        .unwrap();
+        match PinInner::unlock(keystore, &pin) {
+            Ok(inner) => return Some(inner),
+            Err(AuthError::IncorrectPin) => {
+                println!("{}", "PIN错误".red().bold());
+            }
+            Err(e) => {
+                println!(
+                    "加载密钥库失败：{}",
+                    ColoredString::from(e.to_string()).red().bold()
+                );
+                return None;
+            }
+        }
+    }
+    None
+}
 fn interact() {
-    let auth = Authenticator {
-        inner: PinInner::default(),
+    // 密钥库路径可以通过环境变量FIDO_CX_KEYSTORE指定
+    let keystore =
+        std::env::var("FIDO_CX_KEYSTORE").unwrap_or_else(|_| DEFAULT_KEYSTORE_PATH.to_string());
+    let Some(inner) = unlock(&keystore) else {
+        return;
     };
+    let mut auth = Authenticator::new(inner);
+    auth.set_consent_provider(CliConsent);
+    // 导出策略文件路径可以通过环境变量FIDO_CX_POLICY指定，策略无效时不继续运行
+    let policy =
+        std::env::var("FIDO_CX_POLICY").unwrap_or_else(|_| DEFAULT_POLICY_PATH.to_string());
+    match Policy::load(&policy) {
+        Ok(policy) => auth.policy = policy,
+        Err(e) => {
+            println!(
+                "策略文件错误：{}",
+                ColoredString::from(e.to_string()).red().bold()
+            );
+            return;
+        }
+    }
 
     banner();
     loop {
@@ -100,6 +479,11 @@ fn interact() {
             "  请求导出凭证",
             "  导出凭证",
             "  导入凭证",
+            "  备份凭证",
+            "  恢复备份",
+            "  与设备配对",
+            "  处理导入回执",
+            "  查看审计日志",
             "  退出",
         ];
         let selection = Select::new()
@@ -112,7 +496,12 @@ fn interact() {
             1 => request(&auth),
             2 => export(&auth),
             3 => import(&auth),
-            4 => {
+            4 => backup(&auth),
+            5 => restore(&auth),
+            6 => pair(&auth),
+            7 => receipt(&auth),
+            8 => audit(&auth),
+            9 => {
                 println!("{}", "退出程序".green());
                 break;
             }
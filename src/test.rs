@@ -1,12 +1,36 @@
+use crate::authenticator::audit::{AuditEvent, AuditLog, AuditOutcome};
+use crate::authenticator::consent::{key_fingerprint, Consent, ConsentProvider, ConsentRequest};
+use crate::authenticator::crypto::{
+    decrypt, encrypt, export_secret_r, export_secret_s, gen_key_pair, Binding, EXPORT_ONLY_AEAD,
+    RECORD_SIZE,
+};
+use crate::authenticator::error::AuthenticatorError as AuthError;
+use crate::authenticator::extension::Extension;
+use crate::authenticator::inner::InnerAuthenticator;
+use crate::authenticator::keystore::KeyEntry;
 use crate::authenticator::pin::PinInner;
+use crate::authenticator::policy::{Policy, Suite};
+use crate::authenticator::protocol::credential::{Credential, StructuredSingleFileCredential};
+use crate::authenticator::protocol::cxf::*;
+use crate::authenticator::protocol::export_error::{ErrorCode, ExportError};
 use crate::authenticator::protocol::hpke_format::HPKEMode::{Auth, AuthPsk, Base, Psk};
-use crate::authenticator::Authenticator;
+use crate::authenticator::protocol::receipt::{ReceiptAction, ReceiptStatus};
+use crate::authenticator::protocol::request::ExportRequest;
+use crate::authenticator::relay::{Delivery, DirectoryRelay};
+use crate::authenticator::{Authenticator, ImportResult};
+use base64::prelude::BASE64_URL_SAFE;
+use base64::Engine;
 use itertools::iproduct;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::Write;
+use std::rc::Rc;
 use std::time::{Duration, Instant};
+use tempfile::TempDir;
 
 pub(crate) fn gen_random_credential(rp_id: &str) -> String {
     // 生成一个长度随机的任意字符串
@@ -24,6 +48,54 @@ pub(crate) fn gen_random_credential(rp_id: &str) -> String {
     random_string
 }
 
+/// 为双方保存同一个随机的预共享密钥，供Psk和AuthPsk模式使用
+pub(crate) fn share_psk<T: InnerAuthenticator>(a: &Authenticator<T>, b: &Authenticator<T>) {
+    let mut psk = [0u8; 32];
+    rand::thread_rng().fill(&mut psk);
+    a.inner.add_psk("shared-psk", &psk).unwrap();
+    b.inner.add_psk("shared-psk", &psk).unwrap();
+}
+
+/// 在临时目录中保存给定的(RP ID, 内容)凭证，返回以该目录为凭证目录的验证器
+pub(crate) fn with_credentials(
+    inner: PinInner,
+    creds: &[(&str, &str)],
+) -> (TempDir, Authenticator<PinInner>) {
+    let dir = tempfile::tempdir().unwrap();
+    for (i, (rp_id, content)) in creds.iter().enumerate() {
+        StructuredSingleFileCredential::new(rp_id.to_string(), content.as_bytes().to_vec())
+            .to_file(dir.path().join(format!("{}.cx", i)))
+            .unwrap();
+    }
+    let mut authenticator = Authenticator::new(inner);
+    authenticator.inner.cred_dir = dir.path().to_path_buf();
+    (dir, authenticator)
+}
+
+/// 使用默认算法并保存了给定凭证的导出方
+pub(crate) fn exporter_with(creds: &[(&str, &str)]) -> (TempDir, Authenticator<PinInner>) {
+    with_credentials(PinInner::default(), creds)
+}
+
+/// 导入方请求导出www.example.com的凭证
+pub(crate) fn request_for(importer: &Authenticator<PinInner>) -> String {
+    importer
+        .construct_export_request("www.example.com".to_string())
+        .expect("Construct Error,Test Failed")
+}
+
+/// 取出CXF数据中所有笔记凭证的内容
+pub(crate) fn note_contents(header: &Header) -> Vec<String> {
+    header
+        .items()
+        .flat_map(|item| item.credentials.iter())
+        .filter_map(|cred| match cred {
+            TypedCredential::Note(note) => Some(note.content.value.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
 #[test]
 fn cx_test() {
     let kem_id = [0x10, 0x11, 0x12, 0x20];
@@ -32,12 +104,9 @@ fn cx_test() {
     let modes = [Base, Auth, Psk, AuthPsk];
 
     for (kem, kdf, aead, mode) in iproduct!(kem_id, kdf_id, aead_id, modes) {
-        let importer = Authenticator {
-            inner: PinInner::new(kem, kdf, aead, &mode),
-        };
-        let exporter = Authenticator {
-            inner: PinInner::new(kem, kdf, aead, &mode),
-        };
+        let importer = Authenticator::new(PinInner::new(kem, kdf, aead, &mode));
+        let exporter = Authenticator::new(PinInner::new(kem, kdf, aead, &mode));
+        share_psk(&importer, &exporter);
         for _i in 0..100 {
             let random_cred = gen_random_credential("www.example.com");
 
@@ -53,19 +122,15 @@ fn cx_test() {
                 .handle_response(export_response)
                 .expect("Handle Error，Test Failed");
 
-            assert_eq!(recv_cred, random_cred);
+            assert_eq!(note_contents(&recv_cred.header), vec![random_cred]);
         }
     }
 }
 
 #[test]
 fn process_test() {
-    let importer = Authenticator {
-        inner: PinInner::default(),
-    };
-    let exporter = Authenticator {
-        inner: PinInner::default(),
-    };
+    let importer = Authenticator::new(PinInner::default());
+    let exporter = Authenticator::new(PinInner::default());
     let random_cred = gen_random_credential("www.example.com");
 
     let export_request = importer
@@ -80,7 +145,1556 @@ fn process_test() {
         .handle_response(export_response)
         .expect("Handle Error，Test Failed");
 
-    assert_eq!(recv_cred, random_cred);
+    assert_eq!(note_contents(&recv_cred.header), vec![random_cred]);
+}
+
+#[test]
+fn keystore_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("keystore.json");
+
+    let importer =
+        Authenticator::new(PinInner::open(&path).expect("Open Keystore Error,Test Failed"));
+    let exporter = Authenticator::new(PinInner::default());
+    let export_request = request_for(&importer);
+    let export_response = exporter
+        .handle_request(export_request)
+        .expect("Handle Error,Test Failed");
+
+    // 模拟重启：重新从密钥库加载的验证器应当能够处理之前请求的响应
+    let restarted =
+        Authenticator::new(PinInner::open(&path).expect("Open Keystore Error,Test Failed"));
+    assert_eq!(restarted.inner.keys, importer.inner.keys);
+    restarted
+        .handle_response(export_response)
+        .expect("Handle Error，Test Failed");
+}
+
+#[test]
+fn encrypted_keystore_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("keystore.json");
+
+    let inner = PinInner::unlock(&path, "123456").expect("Unlock Error,Test Failed");
+    let content = std::fs::read_to_string(&path).unwrap();
+    let (sk, _) = &inner.keys[&0x10];
+    assert!(!content.contains(&BASE64_URL_SAFE.encode(sk)));
+
+    let reopened = PinInner::unlock(&path, "123456").expect("Unlock Error,Test Failed");
+    assert_eq!(reopened.keys, inner.keys);
+
+    assert!(matches!(
+        PinInner::unlock(&path, "654321"),
+        Err(AuthError::IncorrectPin)
+    ));
+}
+
+#[test]
+fn key_pair_test() {
+    // 导出方声明支持P-384但没有预先生成密钥，Auth模式下按需生成身份密钥
+    let (_exporter_dir, mut exporter) = with_credentials(
+        PinInner::new(0x10, 1, 1, &Auth),
+        &[("www.example.com", "lazy")],
+    );
+    let importer = Authenticator::new(PinInner::new(0x11, 1, 1, &Auth));
+    let offered = importer.inner.support_algorithms().0;
+    exporter.inner.algorithms.extend(offered);
+    let export_request = request_for(&importer);
+    let export_response = exporter
+        .handle_request(export_request)
+        .expect("Handle Error,Test Failed");
+    let import = importer
+        .handle_response(export_response)
+        .expect("Handle Error，Test Failed");
+    assert_eq!(note_contents(&import.header), ["lazy"]);
+    let generated = exporter.inner.key_pair(0x11).unwrap();
+    assert_eq!(exporter.inner.key_pair(0x11).unwrap(), generated);
+    let kems = |event: AuditEvent| -> Vec<Option<u16>> {
+        exporter
+            .inner
+            .audit_log()
+            .entries()
+            .iter()
+            .filter(|entry| entry.record.event == event)
+            .map(|entry| entry.record.kem)
+            .collect()
+    };
+    assert_eq!(kems(AuditEvent::KeyGenerated), [Some(0x10), Some(0x11)]);
+    assert_eq!(kems(AuditEvent::IdentityKeyGenerated), [Some(0x11)]);
+
+    // HPKE不支持或本实现未启用的KEM返回错误而不是崩溃，也不会生成密钥
+    for kem in [0x99, 0x20, 0x21] {
+        assert!(matches!(
+            exporter.inner.key_pair(kem),
+            Err(AuthError::KeyNotFound(k)) if k == kem
+        ));
+    }
+    assert_eq!(kems(AuditEvent::KeyGenerated), [Some(0x10), Some(0x11)]);
+    assert!(exporter.inner.identity_key_pair(0x21).is_err());
+
+    // 伪造的响应使用请求中未提供的KEM时，在查找密钥前就被拒绝
+    let importer = Authenticator::new(PinInner::new(0x10, 1, 1, &Auth));
+    let export_request = request_for(&importer);
+    let response = exporter.handle_request(export_request).unwrap();
+    for kem in [0x11, 0x21] {
+        let mut forged: Value = serde_json::from_str(&response).unwrap();
+        forged["hpke_parameters"]["kem"] = json!(kem);
+        assert!(matches!(
+            importer.handle_response(forged.to_string()),
+            Err(AuthError::UnsupportedAlgorithm)
+        ));
+    }
+    let generated = importer
+        .inner
+        .audit_log()
+        .entries()
+        .iter()
+        .filter(|entry| entry.record.event == AuditEvent::KeyGenerated)
+        .map(|entry| entry.record.kem)
+        .collect::<Vec<_>>();
+    assert_eq!(generated, [Some(0x10)]);
+    // 伪造的响应不会消耗请求
+    let import = importer
+        .handle_response(response)
+        .expect("Handle Error，Test Failed");
+    assert_eq!(note_contents(&import.header), ["lazy"]);
+
+    // 按需生成的密钥保存到密钥库中
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("keystore.json");
+    let inner = PinInner::open(&path).expect("Open Keystore Error,Test Failed");
+    let generated = inner.key_pair(0x12).unwrap();
+    let reopened = PinInner::open(&path).expect("Open Keystore Error,Test Failed");
+    assert_eq!(reopened.keys[&0x12], generated);
+}
+
+#[test]
+fn identity_key_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("keystore.json");
+    let (_exporter_dir, exporter) = with_credentials(
+        PinInner::open(&path).expect("Open Keystore Error,Test Failed"),
+        &[("www.example.com", "identity")],
+    );
+    let importer = Authenticator::new(PinInner::new(0x10, 1, 1, &Auth));
+    let export_request = request_for(&importer);
+    let response = exporter.handle_request(export_request).unwrap();
+
+    // 响应中的pk是导出方的身份公钥，而不是其接收公钥
+    let identity = exporter.inner.identity_key_pair(0x10).unwrap();
+    let recipient = exporter.inner.key_pair(0x10).unwrap();
+    assert_ne!(identity, recipient);
+    let response_json: Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(
+        response_json["hpke_parameters"]["key"]["pk"],
+        json!(BASE64_URL_SAFE.encode(&identity.1))
+    );
+    // 缺少身份公钥或公钥长度错误的响应被拒绝，而不是崩溃
+    for pk in [Value::Null, json!(BASE64_URL_SAFE.encode(&identity.1[1..]))] {
+        let mut forged = response_json.clone();
+        forged["hpke_parameters"]["key"]["pk"] = pk;
+        assert!(matches!(
+            importer.handle_response(forged.to_string()),
+            Err(AuthError::InvalidPublicKey(0x10))
+        ));
+    }
+    let import = importer
+        .handle_response(response)
+        .expect("Handle Error，Test Failed");
+    assert_eq!(note_contents(&import.header), ["identity"]);
+
+    // 身份密钥与接收密钥分开保存，重新打开后保持不变
+    let reopened = PinInner::open(&path).expect("Open Keystore Error,Test Failed");
+    assert_eq!(reopened.identity_key_pair(0x10).unwrap(), identity);
+    assert_eq!(reopened.key_pair(0x10).unwrap(), recipient);
+    let identities = reopened
+        .audit_log()
+        .entries()
+        .iter()
+        .filter(|entry| entry.record.event == AuditEvent::IdentityKeyGenerated)
+        .count();
+    assert_eq!(identities, 1);
+}
+
+#[test]
+fn ephemeral_key_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("keystore.json");
+    let importer =
+        Authenticator::new(PinInner::open(&path).expect("Open Keystore Error,Test Failed"));
+    let exporter = Authenticator::new(PinInner::default());
+    let request = || {
+        let export_request = request_for(&importer);
+        let value: Value = serde_json::from_str(&export_request).unwrap();
+        (export_request, value)
+    };
+    let pk = |value: &Value| value["hpke_parameters"][0]["key"]["pk"].clone();
+
+    // 每个请求使用不同的临时公钥，且不是验证器的静态接收公钥
+    let (export_request, value) = request();
+    let (_, other) = request();
+    assert_ne!(pk(&value), pk(&other));
+    let kem = value["hpke_parameters"][0]["kem"].as_u64().unwrap() as u16;
+    let static_pk = importer.inner.key_pair(kem).unwrap().1;
+    assert_ne!(pk(&value), json!(BASE64_URL_SAFE.encode(static_pk)));
+    // 同一请求中相同KEM的套件共用一个临时密钥
+    let nonce = value["nonce"].as_str().unwrap();
+    let pending = importer.inner.pending_request(nonce).unwrap().unwrap();
+    let mut kems: Vec<u16> = pending.keys.iter().map(|entry| entry.kem).collect();
+    kems.dedup();
+    assert_eq!(kems.len(), pending.keys.len());
+    let ephemeral_sk = pending.keys[0].sk.clone();
+    assert!(std::fs::read_to_string(&path)
+        .unwrap()
+        .contains(&ephemeral_sk));
+
+    // 处理响应后临时私钥被销毁，密钥库中不再保存
+    let export_response = exporter
+        .handle_request(export_request)
+        .expect("Handle Error,Test Failed");
+    let restarted =
+        Authenticator::new(PinInner::open(&path).expect("Open Keystore Error,Test Failed"));
+    restarted
+        .handle_response(export_response)
+        .expect("Handle Error，Test Failed");
+    let pending = restarted.inner.pending_request(nonce).unwrap().unwrap();
+    assert!(pending.consumed_at.is_some() && pending.keys.is_empty());
+    assert!(!std::fs::read_to_string(&path)
+        .unwrap()
+        .contains(&ephemeral_sk));
+
+    // 打开密钥库时销毁已过期请求的临时密钥
+    let (export_request, value) = request();
+    let mut expired: ExportRequest = serde_json::from_str(&export_request).unwrap();
+    expired.expires_at = expired.issued_at - 1;
+    let nonce = value["nonce"].as_str().unwrap();
+    let keys = importer.inner.pending_request(nonce).unwrap().unwrap().keys;
+    let ephemeral_sk = keys[0].sk.clone();
+    importer.inner.save_pending(&expired, keys).unwrap();
+    assert!(std::fs::read_to_string(&path)
+        .unwrap()
+        .contains(&ephemeral_sk));
+    let reopened = PinInner::open(&path).expect("Open Keystore Error,Test Failed");
+    assert!(reopened
+        .pending_request(nonce)
+        .unwrap()
+        .unwrap()
+        .keys
+        .is_empty());
+    assert!(!std::fs::read_to_string(&path)
+        .unwrap()
+        .contains(&ephemeral_sk));
+}
+
+#[test]
+fn cxf_test() {
+    let field = |value: &str| Some(EditableField::new(FieldType::String, value.to_string()));
+    let credentials = vec![
+        TypedCredential::BasicAuth(BasicAuthCredential {
+            username: field("alice"),
+            password: Some(EditableField::new(
+                FieldType::ConcealedString,
+                "pw".to_string(),
+            )),
+        }),
+        TypedCredential::Passkey(PasskeyCredential {
+            credential_id: "Y3JlZA".to_string(),
+            rp_id: "www.example.com".to_string(),
+            username: "alice".to_string(),
+            user_display_name: "Alice".to_string(),
+            user_handle: "dXNlcg".to_string(),
+            key: "a2V5".to_string(),
+        }),
+        TypedCredential::Totp(TotpCredential {
+            secret: "JBSWY3DPEHPK3PXP".to_string(),
+            period: 30,
+            digits: 6,
+            username: None,
+            algorithm: OtpHashAlgorithm::Sha1,
+            issuer: Some("Example".to_string()),
+        }),
+        TypedCredential::Wifi(WifiCredential {
+            ssid: field("home"),
+            network_security_type: Some(EditableField::new(
+                FieldType::WifiNetworkSecurityType,
+                "wpa2-personal".to_string(),
+            )),
+            passphrase: None,
+            hidden: None,
+        }),
+    ];
+    let mut item = Item::from_stored("www.example.com", b"note");
+    item.credentials = credentials;
+
+    let json = serde_json::to_string(&item).unwrap();
+    for tag in [
+        r#""type":"basic-auth""#,
+        r#""type":"passkey""#,
+        r#""type":"totp""#,
+        r#""type":"wifi""#,
+    ] {
+        assert!(json.contains(tag));
+    }
+    // 存储内容是条目JSON的凭证导出时应原样还原
+    let stored =
+        StructuredSingleFileCredential::new("www.example.com".to_string(), json.into_bytes());
+    assert_eq!(stored.to_item(), item);
+}
+
+#[test]
+fn multi_credential_test() {
+    let (_exporter_dir, exporter) = exporter_with(&[
+        ("www.example.com", "first"),
+        ("www.example.com", "second"),
+        ("www.example.com", "second"),
+        ("other.example.com", "other"),
+    ]);
+    let (_importer_dir, importer) = with_credentials(PinInner::default(), &[]);
+
+    let export_request = request_for(&importer);
+    let export_response = exporter
+        .handle_request(export_request)
+        .expect("Handle Error,Test Failed");
+    let mut import = importer
+        .handle_response(export_response)
+        .expect("Handle Error，Test Failed");
+    import.confirm();
+    let header = &import.header;
+
+    let mut contents = note_contents(header);
+    contents.sort();
+    assert_eq!(contents, ["first", "second", "second"]);
+    let ids: HashSet<&String> = header.items().map(|item| &item.id).collect();
+    assert_eq!(ids.len(), 3);
+
+    assert_eq!(importer.commit_import(&import).unwrap(), 3);
+    let stored: Vec<Item> = importer
+        .inner
+        .get_credentials()
+        .unwrap()
+        .iter()
+        .map(|cred| cred.to_item())
+        .collect();
+    assert_eq!(stored.len(), 3);
+    assert!(header.items().all(|item| stored.contains(item)));
+}
+
+#[test]
+fn credential_type_test() {
+    let (_exporter_dir, exporter) = exporter_with(&[]);
+    let field = |value: &str| Some(EditableField::new(FieldType::String, value.to_string()));
+    let mut login = Item::from_stored("www.example.com", b"login note");
+    login
+        .credentials
+        .push(TypedCredential::BasicAuth(BasicAuthCredential {
+            username: field("alice"),
+            password: field("secret"),
+        }));
+    let mut passkey = Item::from_stored("www.example.com", b"passkey");
+    passkey.credentials = vec![TypedCredential::Passkey(PasskeyCredential {
+        credential_id: "Y3JlZA".to_string(),
+        rp_id: "www.example.com".to_string(),
+        username: "alice".to_string(),
+        user_display_name: "Alice".to_string(),
+        user_handle: "dXNlcg".to_string(),
+        key: "a2V5".to_string(),
+    })];
+    for (file, item) in [("login.cx", &login), ("passkey.cx", &passkey)] {
+        StructuredSingleFileCredential::from_item(item)
+            .unwrap()
+            .to_file(exporter.inner.cred_dir.join(file))
+            .unwrap();
+    }
+    let mut importer = Authenticator::new(PinInner::default());
+    importer.inner.credential_types = vec![CredentialType::BasicAuth];
+
+    let export_request = request_for(&importer);
+    let export_response = exporter
+        .handle_request(export_request)
+        .expect("Handle Error,Test Failed");
+    let import = importer
+        .handle_response(export_response)
+        .expect("Handle Error，Test Failed");
+
+    let items: Vec<&Item> = import.header.items().collect();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].id, login.id);
+    assert!(items[0]
+        .credentials
+        .iter()
+        .all(|cred| cred.credential_type() == CredentialType::BasicAuth));
+    assert_eq!(
+        import.skipped_types,
+        [CredentialType::Passkey, CredentialType::Note]
+    );
+
+    // 不限制或接受所有凭证类型时请求中不列出类型，导出所有凭证
+    for types in [Vec::new(), CredentialType::ALL.to_vec()] {
+        importer.inner.credential_types = types;
+        let export_request = request_for(&importer);
+        let request: Value = serde_json::from_str(&export_request).unwrap();
+        assert_eq!(request["credential_types"], Value::Null);
+        let export_response = exporter
+            .handle_request(export_request)
+            .expect("Handle Error,Test Failed");
+        let import = importer
+            .handle_response(export_response)
+            .expect("Handle Error，Test Failed");
+        assert_eq!(import.header.items().count(), 2);
+        assert!(import.skipped_types.is_empty());
+    }
+}
+
+/// 测试用扩展：导入方在请求中给出标签，导出方为每个条目加上该标签，导入方为收到的条目设置副标题
+struct TagExtension;
+
+impl Extension for TagExtension {
+    fn name(&self) -> &str {
+        "tag"
+    }
+    fn request_data(&self) -> Option<Value> {
+        Some(json!({ "tag": "imported" }))
+    }
+    fn on_export(&self, items: &mut Vec<Item>, data: Option<&Value>) -> Result<(), AuthError> {
+        let tag = data.and_then(|data| data["tag"].as_str()).unwrap_or("none");
+        for item in items.iter_mut() {
+            item.tags.get_or_insert_with(Vec::new).push(tag.to_string());
+        }
+        Ok(())
+    }
+    fn on_import(&self, header: &mut Header) -> Result<(), AuthError> {
+        for item in header.accounts.iter_mut().flat_map(|a| a.items.iter_mut()) {
+            item.subtitle = Some("tagged".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn extension_test() {
+    let mut importer = Authenticator::new(PinInner::default());
+    importer.register_extension(TagExtension);
+    let (_exporter_dir, mut exporter) = exporter_with(&[("www.example.com", "tagged")]);
+    exporter.register_extension(TagExtension);
+    let mut plain_exporter = Authenticator::new(PinInner::default());
+    plain_exporter.inner.cred_dir = exporter.inner.cred_dir.clone();
+
+    // 每个请求只能处理一次响应
+    let export_request = || request_for(&importer);
+
+    // 双方都注册了扩展
+    let import = importer
+        .handle_response(exporter.handle_request(export_request()).unwrap())
+        .expect("Handle Error，Test Failed");
+    assert_eq!(note_contents(&import.header), ["tagged"]);
+    assert!(import.header.items().all(|item| {
+        item.tags == Some(vec!["imported".to_string()])
+            && item.subtitle.as_deref() == Some("tagged")
+    }));
+
+    // 导出方未注册扩展时不应用
+    let import = importer
+        .handle_response(plain_exporter.handle_request(export_request()).unwrap())
+        .expect("Handle Error，Test Failed");
+    assert!(import
+        .header
+        .items()
+        .all(|item| item.tags.is_none() && item.subtitle.is_none()));
+
+    // 未知的扩展及其数据被忽略
+    let mut request: Value = serde_json::from_str(&export_request()).unwrap();
+    request["known_extensions"] = json!(["future-extension", "tag"]);
+    request["extensions"]["future-extension"] = json!({ "anything": [1, 2, 3] });
+    // 请求内容绑定在HPKE info中，导入方保存的请求需与发出的一致
+    let sent: ExportRequest = serde_json::from_value(request.clone()).unwrap();
+    let keys = importer
+        .inner
+        .pending_request(&sent.nonce)
+        .unwrap()
+        .unwrap()
+        .keys;
+    importer.inner.save_pending(&sent, keys).unwrap();
+    let response = exporter
+        .handle_request(request.to_string())
+        .expect("Handle Error,Test Failed");
+    let response_json: Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(response_json["extensions"], json!(["tag"]));
+    importer
+        .handle_response(response)
+        .expect("Handle Error，Test Failed");
+
+    // CXF中无法识别的扩展原样保留
+    let mut item = serde_json::to_value(Item::from_stored("www.example.com", b"note")).unwrap();
+    item["extensions"] = json!([{ "name": "future-extension", "payload": { "a": 1 } }]);
+    let parsed: Item = serde_json::from_value(item.clone()).unwrap();
+    assert_eq!(parsed.extensions[0].name, "future-extension");
+    assert_eq!(serde_json::to_value(&parsed).unwrap(), item);
+}
+
+#[test]
+fn indirect_test() {
+    let relay_dir = tempfile::tempdir().unwrap();
+    let (_exporter_dir, exporter) = exporter_with(&[("www.example.com", "relayed secret")]);
+    let importer = Authenticator::new(PinInner::default());
+    let relay = DirectoryRelay::new(relay_dir.path());
+
+    let export_request = importer
+        .construct_indirect_request("www.example.com".to_string(), "mailbox-1".to_string())
+        .expect("Construct Error,Test Failed");
+    // 响应未投递前信箱为空
+    assert!(importer
+        .fetch_response("mailbox-1", &relay)
+        .unwrap()
+        .is_none());
+    let delivery = exporter
+        .respond(export_request.clone(), &relay)
+        .expect("Handle Error,Test Failed");
+    assert!(matches!(delivery, Delivery::Relayed(ref mailbox) if mailbox == "mailbox-1"));
+    // 中继只能看到密文
+    let posted = std::fs::read_to_string(relay_dir.path().join("mailbox-1.json")).unwrap();
+    assert!(!posted.contains("relayed secret"));
+
+    let import = importer
+        .fetch_response("mailbox-1", &relay)
+        .expect("Handle Error，Test Failed")
+        .expect("Response not relayed");
+    assert_eq!(note_contents(&import.header), ["relayed secret"]);
+    let reveal = importer.sas_reveal(&import).unwrap();
+    assert_eq!(exporter.verify_sas_reveal(reveal).unwrap(), import.sas);
+    // 响应被取回后从信箱中移除
+    assert!(importer
+        .fetch_response("mailbox-1", &relay)
+        .unwrap()
+        .is_none());
+
+    // 缺少信箱或信箱名非法的请求被拒绝
+    let mut request: Value = serde_json::from_str(&export_request).unwrap();
+    request["relay"] = Value::Null;
+    assert!(matches!(
+        exporter.respond(request.to_string(), &relay),
+        Err(AuthError::RequestNotAllowed(_))
+    ));
+    request["relay"] = json!("../escape");
+    assert!(matches!(
+        exporter.respond(request.to_string(), &relay),
+        Err(AuthError::RequestNotAllowed(_))
+    ));
+    assert!(!relay_dir.path().join("../escape.json").exists());
+}
+
+#[test]
+fn self_backup_test() {
+    let keystore_dir = tempfile::tempdir().unwrap();
+    let keystore = keystore_dir.path().join("keystore.json");
+    let (_cred_dir, authenticator) = with_credentials(
+        PinInner::open(&keystore).unwrap(),
+        &[
+            ("www.example.com", "first"),
+            ("other.example.com", "second"),
+        ],
+    );
+
+    // 自备份包含所有RP的凭证
+    let backup = authenticator.backup().expect("Backup Error,Test Failed");
+    assert!(!backup.contains("first") && !backup.contains("second"));
+
+    // 使用相同密钥重建的验证器可以恢复
+    let (_restore_dir, rebuilt) = with_credentials(PinInner::open(&keystore).unwrap(), &[]);
+    assert_eq!(rebuilt.restore(backup.clone()).unwrap(), 2);
+    let items = |a: &Authenticator<PinInner>| -> HashSet<String> {
+        a.inner
+            .get_credentials()
+            .unwrap()
+            .iter()
+            .map(|cred| serde_json::to_string(&cred.to_item()).unwrap())
+            .collect()
+    };
+    assert_eq!(items(&rebuilt), items(&authenticator));
+    // 已存在的凭证不会重复恢复
+    assert_eq!(authenticator.restore(backup.clone()).unwrap(), 0);
+
+    // 备份文件中的套件与请求不一致时拒绝，请求中不支持的KEM不会生成密钥
+    let mut forged: Value = serde_json::from_str(&backup).unwrap();
+    forged["response"]["hpke_parameters"]["kem"] = json!(0x21);
+    assert!(matches!(
+        rebuilt.restore(forged.to_string()),
+        Err(AuthError::UnsupportedAlgorithm)
+    ));
+    forged["request"]["hpke_parameters"] = json!([forged["response"]["hpke_parameters"]]);
+    assert!(matches!(
+        rebuilt.restore(forged.to_string()),
+        Err(AuthError::KeyNotFound(0x21))
+    ));
+
+    // 其他验证器无法解密备份
+    let other = Authenticator::new(PinInner::default());
+    assert!(other.restore(backup).is_err());
+
+    // 加密给其他公钥的自备份请求被拒绝
+    let export_request = other
+        .construct_export_request("www.example.com".to_string())
+        .unwrap();
+    let mut request: Value = serde_json::from_str(&export_request).unwrap();
+    request["mode"] = json!("self");
+    assert!(matches!(
+        authenticator.handle_request(request.to_string()),
+        Err(AuthError::RequestNotAllowed(_))
+    ));
+}
+
+#[test]
+fn version_test() {
+    let (_exporter_dir, exporter) = exporter_with(&[("www.example.com", "versioned")]);
+    let importer = Authenticator::new(PinInner::default());
+    let export_request = request_for(&importer);
+    let mut request: Value = serde_json::from_str(&export_request).unwrap();
+
+    // 不支持的版本且无法降级
+    request["version"] = json!(7);
+    request["supported_versions"] = Value::Null;
+    assert!(matches!(
+        exporter.handle_request(request.to_string()),
+        Err(AuthError::UnsupportedVersion(7))
+    ));
+    // 导入方同时支持旧版本时降级
+    request["supported_versions"] = json!([0, 7]);
+    // 导入方记录的是实际发出的请求，临时密钥不变
+    let nonce = request["nonce"].as_str().unwrap();
+    let keys = importer.inner.pending_request(nonce).unwrap().unwrap().keys;
+    importer
+        .inner
+        .save_pending(&serde_json::from_value(request.clone()).unwrap(), keys)
+        .unwrap();
+    let response = exporter
+        .handle_request(request.to_string())
+        .expect("Handle Error,Test Failed");
+    let mut response: Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(response["version"], json!(0));
+    importer
+        .handle_response(response.to_string())
+        .expect("Handle Error，Test Failed");
+
+    // 响应版本与请求不符时拒绝
+    response["version"] = json!(3);
+    assert!(matches!(
+        importer.handle_response(response.to_string()),
+        Err(AuthError::UnsupportedVersion(3))
+    ));
+    // 本地支持但请求中未声明的版本同样拒绝
+    let export_request = request_for(&importer);
+    let response = exporter.handle_request(export_request.clone()).unwrap();
+    let sent: ExportRequest = serde_json::from_str(&export_request).unwrap();
+    let keys = importer
+        .inner
+        .pending_request(&sent.nonce)
+        .unwrap()
+        .unwrap()
+        .keys;
+    let mut newer = sent.clone();
+    newer.version = 7;
+    newer.supported_versions = Some(vec![7]);
+    importer.inner.save_pending(&newer, keys.clone()).unwrap();
+    assert!(matches!(
+        importer.handle_response(response.clone()),
+        Err(AuthError::UnsupportedVersion(0))
+    ));
+    importer.inner.save_pending(&sent, keys).unwrap();
+    importer
+        .handle_response(response)
+        .expect("Handle Error，Test Failed");
+
+    // 缺少版本号的消息无法解析
+    request.as_object_mut().unwrap().remove("version");
+    assert!(matches!(
+        exporter.handle_request(request.to_string()),
+        Err(AuthError::CodeError(_))
+    ));
+}
+
+#[test]
+fn request_validation_test() {
+    let exporter = Authenticator::new(PinInner::default());
+    let importer = Authenticator::new(PinInner::default());
+    let export_request = request_for(&importer);
+    let base: Value = serde_json::from_str(&export_request).unwrap();
+    let check = |modify: &dyn Fn(&mut Value)| {
+        let mut request = base.clone();
+        modify(&mut request);
+        exporter.handle_request(request.to_string())
+    };
+
+    assert!(matches!(
+        check(&|r| r["hpke_parameters"] = json!([])),
+        Err(AuthError::InvalidRequest(_))
+    ));
+    assert!(matches!(
+        check(&|r| r["archive"] = json!([])),
+        Err(AuthError::InvalidRequest(_))
+    ));
+    assert!(matches!(
+        check(&|r| {
+            let first = r["hpke_parameters"][0].clone();
+            r["hpke_parameters"].as_array_mut().unwrap().push(first);
+        }),
+        Err(AuthError::InvalidRequest(_))
+    ));
+    assert!(matches!(
+        check(&|r| r["credential_types"] = json!(vec!["note"; 33])),
+        Err(AuthError::InvalidRequest(_))
+    ));
+    // 公钥缺失或长度错误时返回错误而不是panic
+    assert!(matches!(
+        check(&|r| r["hpke_parameters"][0]["key"]["pk"] = Value::Null),
+        Err(AuthError::InvalidPublicKey(0x10))
+    ));
+    assert!(matches!(
+        check(&|r| r["hpke_parameters"][1]["key"]["pk"] = json!(BASE64_URL_SAFE.encode([4u8; 20]))),
+        Err(AuthError::InvalidPublicKey(0x11))
+    ));
+    for importer in [
+        "",
+        "../etc",
+        "-bad.example.com",
+        "www..example.com",
+        "a b.com",
+    ] {
+        assert!(matches!(
+            check(&|r| r["importer"] = json!(importer)),
+            Err(AuthError::InvalidRequest(_))
+        ));
+    }
+}
+
+#[test]
+fn transcript_binding_test() {
+    let keystore_dir = tempfile::tempdir().unwrap();
+    let keystore = keystore_dir.path().join("keystore.json");
+    let (_exporter_dir, exporter) = exporter_with(&[("www.example.com", "bound")]);
+    let importer = Authenticator::new(PinInner::open(&keystore).unwrap());
+
+    let first = request_for(&importer);
+    let second = request_for(&importer);
+    let second_nonce = serde_json::from_str::<Value>(&second).unwrap()["nonce"].clone();
+    let response: Value = serde_json::from_str(&exporter.handle_request(first).unwrap()).unwrap();
+
+    // 响应不能用于另一个请求
+    let mut replayed = response.clone();
+    replayed["nonce"] = second_nonce;
+    assert!(matches!(
+        importer.handle_response(replayed.to_string()),
+        Err(AuthError::CryptoError(_))
+    ));
+    // 明文字段被篡改时解密失败
+    let mut tampered = response.clone();
+    tampered["skipped_credential_types"] = json!(["totp"]);
+    assert!(matches!(
+        importer.handle_response(tampered.to_string()),
+        Err(AuthError::CryptoError(_))
+    ));
+    // 请求中的凭证类型或扩展数据在途中被篡改时，导入方解密失败
+    for (field, value) in [
+        ("credential_types", json!(["note"])),
+        ("known_extensions", json!(["future-extension"])),
+        ("extensions", json!({ "future-extension": { "a": 1 } })),
+    ] {
+        let export_request = request_for(&importer);
+        let mut request: Value = serde_json::from_str(&export_request).unwrap();
+        request[field] = value;
+        let response = exporter.handle_request(request.to_string()).unwrap();
+        assert!(matches!(
+            importer.handle_response(response),
+            Err(AuthError::CryptoError(_))
+        ));
+    }
+    // 未发出过的请求
+    let mut unknown = response.clone();
+    unknown["nonce"] = json!("AAAAAAAAAAAAAAAAAAAAAA");
+    assert!(matches!(
+        importer.handle_response(unknown.to_string()),
+        Err(AuthError::UnknownRequest)
+    ));
+
+    // 已发出的请求保存在密钥库中，重启后仍可处理响应
+    let reopened = Authenticator::new(PinInner::open(&keystore).unwrap());
+    let import = reopened
+        .handle_response(response.to_string())
+        .expect("Handle Error，Test Failed");
+    assert_eq!(note_contents(&import.header), ["bound"]);
+}
+
+#[test]
+fn replay_test() {
+    let (_exporter_dir, exporter) = exporter_with(&[("www.example.com", "replayed")]);
+    let importer = Authenticator::new(PinInner::default());
+
+    // 同一响应只能导入一次
+    let export_request = request_for(&importer);
+    let response = exporter.handle_request(export_request.clone()).unwrap();
+    importer
+        .handle_response(response.clone())
+        .expect("Handle Error，Test Failed");
+    assert!(matches!(
+        importer.handle_response(response.clone()),
+        Err(AuthError::RequestConsumed)
+    ));
+    // 未发出过的请求
+    let other = Authenticator::new(PinInner::default());
+    assert!(matches!(
+        other.handle_response(response),
+        Err(AuthError::UnknownRequest)
+    ));
+
+    // 导出方拒绝过期的请求
+    let mut request: Value = serde_json::from_str(&export_request).unwrap();
+    request["issued_at"] = json!(1_000);
+    request["expires_at"] = json!(1_600);
+    assert!(matches!(
+        exporter.handle_request(request.to_string()),
+        Err(AuthError::RequestExpired)
+    ));
+    request["expires_at"] = json!(1_000);
+    assert!(matches!(
+        exporter.handle_request(request.to_string()),
+        Err(AuthError::InvalidRequest(_))
+    ));
+
+    // 导入方拒绝对过期请求的响应
+    let export_request = request_for(&importer);
+    let response = exporter.handle_request(export_request.clone()).unwrap();
+    let mut expired: ExportRequest = serde_json::from_str(&export_request).unwrap();
+    expired.expires_at = expired.issued_at - 1;
+    let pending = |nonce: &str| importer.inner.pending_request(nonce).unwrap().unwrap();
+    let keys = pending(&expired.nonce).keys;
+    importer.inner.save_pending(&expired, keys).unwrap();
+    assert!(matches!(
+        importer.handle_response(response),
+        Err(AuthError::RequestExpired)
+    ));
+    // 发现请求过期时销毁其临时密钥
+    assert!(pending(&expired.nonce).keys.is_empty());
+}
+
+#[test]
+fn psk_test() {
+    for mode in [Psk, AuthPsk] {
+        let importer = Authenticator::new(PinInner::new(0x10, 1, 1, &mode));
+        let (_exporter_dir, exporter) = with_credentials(
+            PinInner::new(0x10, 1, 1, &mode),
+            &[("www.example.com", "psk")],
+        );
+        let exchange = || {
+            let export_request = request_for(&importer);
+            let response = exporter.handle_request(export_request)?;
+            importer.handle_response(response)
+        };
+
+        // 导出方没有请求中指定的PSK
+        importer.inner.add_psk("device-a", &[1u8; 32]).unwrap();
+        assert!(matches!(exchange(), Err(AuthError::UnknownPsk(id)) if id == "device-a"));
+        // psk_id相同但密钥不同
+        exporter.inner.add_psk("device-a", &[2u8; 32]).unwrap();
+        assert!(matches!(exchange(), Err(AuthError::CryptoError(_))));
+        // 密钥一致
+        exporter.inner.add_psk("device-a", &[1u8; 32]).unwrap();
+        let import = exchange().expect("Handle Error，Test Failed");
+        assert_eq!(note_contents(&import.header), ["psk"]);
+    }
+    // PSK较多时请求中的套件数仍不超过上限，导出方可以正常处理
+    let importer = Authenticator::new(PinInner::default());
+    let (_exporter_dir, exporter) = exporter_with(&[("www.example.com", "psk")]);
+    for i in 0..6 {
+        let psk = [i as u8 + 1; 32];
+        importer
+            .inner
+            .add_psk(&format!("device-{i}"), &psk)
+            .unwrap();
+        exporter
+            .inner
+            .add_psk(&format!("device-{i}"), &psk)
+            .unwrap();
+    }
+    let export_request = request_for(&importer);
+    let sent: ExportRequest = serde_json::from_str(&export_request).unwrap();
+    assert!(sent.hpke_parameters.len() <= 16);
+    assert!(sent.hpke_parameters.iter().any(|param| param.uses_psk()));
+    let response = exporter.handle_request(export_request).unwrap();
+    importer
+        .handle_response(response)
+        .expect("Handle Error，Test Failed");
+
+    // PSK长度不足
+    let inner = PinInner::default();
+    assert!(inner.add_psk("short", &[0u8; 16]).is_err());
+}
+
+#[test]
+fn pairing_test() {
+    let importer = Authenticator::new(PinInner::default());
+    let (_exporter_dir, exporter) = exporter_with(&[("www.example.com", "paired")]);
+
+    // 配对码不一致时发起方校验响应方的确认值失败，不保存密钥
+    let (session, start) = importer.start_pairing("123456").unwrap();
+    let (_, response) = exporter.respond_pairing("654321", &start).unwrap();
+    assert!(matches!(
+        importer.finish_pairing(session, &response),
+        Err(AuthError::PairingFailed(_))
+    ));
+    // 知道发起方配对码的中间人转发消息，发起方的确认消息到达响应方时校验失败
+    let mitm = Authenticator::new(PinInner::default());
+    let (session, start) = importer.start_pairing("123456").unwrap();
+    let (other, _) = exporter.respond_pairing("654321", &start).unwrap();
+    let (_, relayed) = mitm.respond_pairing("123456", &start).unwrap();
+    let (_, confirm) = importer.finish_pairing(session, &relayed).unwrap();
+    assert!(matches!(
+        exporter.finish_pairing(other, &confirm.unwrap()),
+        Err(AuthError::PairingFailed(_))
+    ));
+    assert!(exporter.inner.psk_ids().is_empty());
+    let importer = Authenticator::new(PinInner::default());
+    assert!(matches!(
+        importer.start_pairing("123"),
+        Err(AuthError::PairingFailed(_))
+    ));
+
+    // 配对码一致
+    let (session, start) = importer.start_pairing("123456").unwrap();
+    let (other, response) = exporter.respond_pairing("123456", &start).unwrap();
+    let (psk_id, confirm) = importer.finish_pairing(session, &response).unwrap();
+    let (peer_id, none) = exporter.finish_pairing(other, &confirm.unwrap()).unwrap();
+    assert!(none.is_none());
+    assert_eq!(psk_id, peer_id);
+    assert_eq!(
+        importer.inner.psk(&psk_id).unwrap(),
+        exporter.inner.psk(&psk_id).unwrap()
+    );
+
+    // 配对后的交换使用AuthPsk模式
+    let export_request = request_for(&importer);
+    let response = exporter.handle_request(export_request).unwrap();
+    let response_json: Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(response_json["hpke_parameters"]["mode"], json!("auth-psk"));
+    assert_eq!(response_json["hpke_parameters"]["psk_id"], json!(psk_id));
+    let import = importer
+        .handle_response(response)
+        .expect("Handle Error，Test Failed");
+    assert_eq!(note_contents(&import.header), ["paired"]);
+}
+
+#[test]
+fn sas_test() {
+    let relay_dir = tempfile::tempdir().unwrap();
+    let relay = DirectoryRelay::new(relay_dir.path());
+    let base = || PinInner::new(0x10, 1, 1, &Base);
+    let (_exporter_dir, exporter) = with_credentials(base(), &[("www.example.com", "sas")]);
+    let (_importer_dir, importer) = with_credentials(base(), &[]);
+    let (_mitm_dir, mitm) = with_credentials(base(), &[]);
+
+    // 导入方揭示承诺的随机数后，双方得到相同的6位短认证码
+    let export_request = request_for(&importer);
+    let request: Value = serde_json::from_str(&export_request).unwrap();
+    assert!(request["sas_commitment"].is_string());
+    let Delivery::Direct(response) = exporter.respond(export_request, &relay).unwrap() else {
+        panic!("直接模式应直接返回响应");
+    };
+    let mut import = importer
+        .handle_response(response)
+        .expect("Handle Error，Test Failed");
+    let reveal = importer.sas_reveal(&import).unwrap();
+    let sas = exporter.verify_sas_reveal(reveal.clone()).unwrap();
+    assert_eq!(import.sas, sas);
+    assert!(sas.len() == 6 && sas.chars().all(|c| c.is_ascii_digit()));
+    // 与承诺不一致的随机数被拒绝
+    let mut wrong: Value = serde_json::from_str(&reveal).unwrap();
+    wrong["reveal"] = json!("AAAAAAAAAAAAAAAAAAAAAA");
+    assert!(matches!(
+        exporter.verify_sas_reveal(wrong.to_string()),
+        Err(AuthError::InvalidSasReveal)
+    ));
+    // 未确认短认证码时不会存储
+    assert!(matches!(
+        importer.commit_import(&import),
+        Err(AuthError::SasNotConfirmed)
+    ));
+    assert!(importer.inner.get_credentials().unwrap().is_empty());
+    import.confirm();
+    assert_eq!(importer.commit_import(&import).unwrap(), 1);
+
+    // 中间人替换请求中的公钥，分别与双方完成交换，双方的短认证码不同
+    let export_request = request_for(&importer);
+    let mut swapped: Value = serde_json::from_str(&export_request).unwrap();
+    let mitm_key = KeyEntry::generate(0x10).unwrap();
+    swapped["hpke_parameters"][0]["key"]["pk"] = json!(mitm_key.pk);
+    let swapped: ExportRequest = serde_json::from_value(swapped).unwrap();
+    mitm.inner.save_pending(&swapped, vec![mitm_key]).unwrap();
+    let Delivery::Direct(response) = exporter.respond(String::from(swapped), &relay).unwrap()
+    else {
+        panic!("直接模式应直接返回响应");
+    };
+    let mut stolen = mitm.handle_response(response).unwrap();
+    stolen.confirm();
+    mitm.commit_import(&stolen).unwrap();
+    let forged = mitm.handle_request(export_request).unwrap();
+    let import = importer
+        .handle_response(forged)
+        .expect("Handle Error，Test Failed");
+    assert_eq!(note_contents(&import.header), ["sas"]);
+    // 中间人发出响应后才得知随机数，转发导入方的揭示消息时双方的认证码不同
+    let exporter_sas = exporter
+        .verify_sas_reveal(importer.sas_reveal(&import).unwrap())
+        .unwrap();
+    assert_ne!(import.sas, exporter_sas);
+
+    // 缺少承诺的请求被拒绝
+    let mut request: Value = serde_json::from_str(&request_for(&importer)).unwrap();
+    request["sas_commitment"] = Value::Null;
+    assert!(matches!(
+        exporter.handle_request(request.to_string()),
+        Err(AuthError::InvalidRequest(_))
+    ));
+}
+
+#[test]
+fn secret_export_test() {
+    let recipient = gen_key_pair(0x20).unwrap();
+    let sender = gen_key_pair(0x20).unwrap();
+    let binding = Binding {
+        info: b"info",
+        aad: b"aad",
+    };
+    for aead in [1, EXPORT_ONLY_AEAD] {
+        let (cipher, enc, sender_ctx) = encrypt(
+            0x20,
+            1,
+            aead,
+            &[],
+            RECORD_SIZE,
+            &recipient.1,
+            &Base,
+            &sender,
+            None,
+            &binding,
+        )
+        .unwrap();
+        let (_, recipient_ctx) = decrypt(
+            0x20,
+            1,
+            aead,
+            &cipher,
+            &recipient.0,
+            &recipient.1,
+            &enc,
+            &Base,
+            &None,
+            None,
+            &binding,
+        )
+        .unwrap();
+        // 同一次交换中双方导出的密钥一致，不同的导出上下文得到不同的密钥
+        let exported = export_secret_s(&*sender_ctx, b"receipt", 32).unwrap();
+        assert_eq!(
+            exported,
+            export_secret_r(&*recipient_ctx, b"receipt", 32).unwrap()
+        );
+        assert_ne!(
+            exported,
+            export_secret_s(&*sender_ctx, b"channel", 32).unwrap()
+        );
+    }
+    // 仅导出的套件不能加密数据
+    assert!(encrypt(
+        0x20,
+        1,
+        EXPORT_ONLY_AEAD,
+        b"data",
+        RECORD_SIZE,
+        &recipient.1,
+        &Base,
+        &sender,
+        None,
+        &binding,
+    )
+    .is_err());
+}
+
+#[test]
+fn chunked_payload_test() {
+    let contents: Vec<String> = (0..4)
+        .map(|_| {
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(1024)
+                .map(char::from)
+                .collect()
+        })
+        .collect();
+    let creds: Vec<(&str, &str)> = contents
+        .iter()
+        .map(|content| ("www.example.com", content.as_str()))
+        .collect();
+    let (_exporter_dir, mut exporter) = exporter_with(&creds);
+    exporter.record_size = 256;
+    let expected: HashSet<String> = contents.iter().cloned().collect();
+    let importer = Authenticator::new(PinInner::default());
+
+    let export_request = request_for(&importer);
+    let response: Value =
+        serde_json::from_str(&exporter.handle_request(export_request).unwrap()).unwrap();
+    let records = response["payload"].as_array().unwrap().clone();
+    assert!(records.len() > 2);
+
+    // 记录被重排、截断或删除时解密失败
+    let tamper = |records: Vec<Value>| {
+        let mut tampered = response.clone();
+        tampered["payload"] = Value::Array(records);
+        importer.handle_response(tampered.to_string())
+    };
+    let mut reordered = records.clone();
+    reordered.swap(0, 1);
+    assert!(matches!(tamper(reordered), Err(AuthError::CryptoError(_))));
+    let truncated = records[..records.len() - 1].to_vec();
+    assert!(matches!(tamper(truncated), Err(AuthError::CryptoError(_))));
+    let mut dropped = records.clone();
+    dropped.remove(1);
+    assert!(matches!(tamper(dropped), Err(AuthError::CryptoError(_))));
+    assert!(matches!(tamper(Vec::new()), Err(AuthError::CryptoError(_))));
+
+    let import = importer
+        .handle_response(response.to_string())
+        .expect("Handle Error，Test Failed");
+    let contents: HashSet<String> = note_contents(&import.header).into_iter().collect();
+    assert_eq!(contents, expected);
+}
+
+#[test]
+fn receipt_test() {
+    let (_exporter_dir, exporter) =
+        exporter_with(&[("www.example.com", "first"), ("www.example.com", "second")]);
+    let (_importer_dir, importer) = with_credentials(PinInner::default(), &[]);
+    let exchange = || {
+        let export_request = request_for(&importer);
+        let response = exporter.handle_request(export_request).unwrap();
+        importer
+            .handle_response(response)
+            .expect("Handle Error，Test Failed")
+    };
+    let source_ids: Vec<String> = exporter
+        .inner
+        .get_credentials()
+        .unwrap()
+        .iter()
+        .map(|cred| cred.to_item().id)
+        .collect();
+
+    // 未确认短认证码时只能报告放弃导入，导出方不会处理凭证
+    let import = exchange();
+    assert!(matches!(
+        importer.import_receipt(&import, ReceiptStatus::Imported),
+        Err(AuthError::SasNotConfirmed)
+    ));
+    let rejected = importer
+        .import_receipt(&import, ReceiptStatus::Rejected)
+        .unwrap();
+    let receipt = exporter
+        .verify_receipt(rejected, ReceiptAction::Delete)
+        .unwrap();
+    assert_eq!(receipt.count, 0);
+    assert_eq!(exporter.inner.get_credentials().unwrap().len(), 2);
+
+    let mut import = exchange();
+    import.confirm();
+    importer.commit_import(&import).unwrap();
+    let receipt = importer
+        .import_receipt(&import, ReceiptStatus::Imported)
+        .unwrap();
+    // 篡改回执的任何字段都会使HMAC校验失败
+    let mut tampered: Value = serde_json::from_str(&receipt).unwrap();
+    tampered["items"].as_array_mut().unwrap().pop();
+    tampered["count"] = json!(1);
+    assert!(matches!(
+        exporter.verify_receipt(tampered.to_string(), ReceiptAction::Delete),
+        Err(AuthError::InvalidReceipt(_))
+    ));
+    let verified = exporter
+        .verify_receipt(receipt.clone(), ReceiptAction::Mark)
+        .unwrap();
+    assert_eq!(verified.status, ReceiptStatus::Imported);
+    assert_eq!(verified.count, 2);
+    assert!(source_ids.iter().all(|id| exporter.inner.is_migrated(id)));
+    // 同一份回执只能处理一次
+    assert!(matches!(
+        exporter.verify_receipt(receipt, ReceiptAction::Delete),
+        Err(AuthError::UnknownRequest)
+    ));
+
+    let mut import = exchange();
+    import.confirm();
+    importer.commit_import(&import).unwrap();
+    let receipt = importer
+        .import_receipt(&import, ReceiptStatus::Imported)
+        .unwrap();
+    exporter
+        .verify_receipt(receipt, ReceiptAction::Delete)
+        .unwrap();
+    assert!(exporter.inner.get_credentials().unwrap().is_empty());
+}
+
+#[test]
+fn export_error_test() {
+    let relay_dir = tempfile::tempdir().unwrap();
+    let relay = DirectoryRelay::new(relay_dir.path());
+    let (_exporter_dir, exporter) = exporter_with(&[]);
+    let importer = Authenticator::new(PinInner::default());
+
+    // 导出方没有凭证，导入方得到类型化的错误
+    let export_request = request_for(&importer);
+    let error = exporter.handle_request(export_request.clone()).unwrap_err();
+    let Delivery::Direct(message) = exporter
+        .reject_request(&export_request, &error, &relay)
+        .unwrap()
+    else {
+        panic!("直接模式应直接返回错误消息");
+    };
+    assert!(matches!(
+        importer.handle_response(message.clone()),
+        Err(AuthError::ExportFailed(ErrorCode::CredentialNotFound, _))
+    ));
+    // 错误消息不对应已发出的请求
+    let mut unknown: Value = serde_json::from_str(&message).unwrap();
+    unknown["nonce"] = json!("AAAAAAAAAAAAAAAAAAAAAA");
+    assert!(matches!(
+        importer.handle_response(unknown.to_string()),
+        Err(AuthError::UnknownRequest)
+    ));
+
+    // 无法解析的请求
+    let error = exporter.handle_request("{}".to_string()).unwrap_err();
+    let Delivery::Direct(message) = exporter.reject_request("{}", &error, &relay).unwrap() else {
+        panic!("直接模式应直接返回错误消息");
+    };
+    assert!(matches!(
+        importer.handle_response(message),
+        Err(AuthError::ExportFailed(ErrorCode::InvalidRequest, _))
+    ));
+    // 版本号有效但内容格式错误的请求同样是无效请求，而不是内部错误
+    for malformed in [
+        "not json",
+        r#"{"version":0,"nonce":"AAAAAAAAAAAAAAAAAAAAAA"}"#,
+        r#"{"version":0,"supported_versions":"0"}"#,
+    ] {
+        let error = exporter.handle_request(malformed.to_string()).unwrap_err();
+        assert_eq!(ErrorCode::from(&error), ErrorCode::InvalidRequest);
+    }
+
+    // 间接模式下错误消息投递到中继
+    let export_request = importer
+        .construct_indirect_request("www.example.com".to_string(), "mailbox-1".to_string())
+        .expect("Construct Error,Test Failed");
+    let error = exporter
+        .respond(export_request.clone(), &relay)
+        .unwrap_err();
+    let delivery = exporter
+        .reject_request(&export_request, &error, &relay)
+        .unwrap();
+    assert!(matches!(delivery, Delivery::Relayed(ref mailbox) if mailbox == "mailbox-1"));
+    assert!(matches!(
+        importer.fetch_response("mailbox-1", &relay),
+        Err(AuthError::ExportFailed(ErrorCode::CredentialNotFound, _))
+    ));
+
+    // 内部错误的细节不会发送给导入方
+    let error = ExportError::new(None, &AuthError::InternalError("/secret/path".to_string()));
+    assert_eq!(error.error, ErrorCode::InternalError);
+    assert!(!error.message.contains("secret"));
+}
+
+/// 用户看到的RP、凭证类型和候选条目id
+type SeenRequest = (String, Option<Vec<CredentialType>>, Vec<String>);
+
+/// 按脚本依次给出决定，并记录看到的请求
+#[derive(Clone, Default)]
+struct ScriptedConsent {
+    decisions: Rc<RefCell<Vec<Consent>>>,
+    seen: Rc<RefCell<Vec<SeenRequest>>>,
+}
+
+impl ConsentProvider for ScriptedConsent {
+    fn consent(&self, request: &ConsentRequest) -> Consent {
+        assert!(!request.importer.key_fingerprint.is_empty());
+        self.seen.borrow_mut().push((
+            request.rp_id.to_string(),
+            request.credential_types.map(<[_]>::to_vec),
+            request
+                .candidates
+                .iter()
+                .map(|item| item.id.clone())
+                .collect(),
+        ));
+        self.decisions.borrow_mut().remove(0)
+    }
+}
+
+#[test]
+fn consent_test() {
+    let consent = ScriptedConsent::default();
+    let (_exporter_dir, mut exporter) =
+        exporter_with(&[("www.example.com", "first"), ("www.example.com", "second")]);
+    exporter.set_consent_provider(consent.clone());
+    let mut importer = Authenticator::new(PinInner::default());
+    importer.inner.credential_types = vec![CredentialType::Note];
+    let export = || {
+        let export_request = request_for(&importer);
+        let response = exporter.handle_request(export_request)?;
+        importer.handle_response(response)
+    };
+
+    // 拒绝时不导出任何凭证
+    consent.decisions.borrow_mut().push(Consent::Deny);
+    assert!(matches!(export(), Err(AuthError::RequestNotAllowed(_))));
+    let (rp_id, types, candidates) = consent.seen.borrow()[0].clone();
+    assert_eq!(rp_id, "www.example.com");
+    assert_eq!(types, Some(vec![CredentialType::Note]));
+    assert_eq!(candidates.len(), 2);
+
+    // 只导出用户选择的凭证
+    let chosen = candidates[0].clone();
+    consent
+        .decisions
+        .borrow_mut()
+        .push(Consent::Subset(vec![chosen.clone()]));
+    let import = export().expect("Handle Error，Test Failed");
+    let ids: Vec<String> = import.header.items().map(|item| item.id.clone()).collect();
+    assert_eq!(ids, [chosen]);
+
+    // 选择的id不在候选中时视为拒绝
+    consent
+        .decisions
+        .borrow_mut()
+        .push(Consent::Subset(vec!["unknown".to_string()]));
+    assert!(matches!(export(), Err(AuthError::RequestNotAllowed(_))));
+
+    consent.decisions.borrow_mut().push(Consent::Approve);
+    assert_eq!(export().unwrap().header.items().count(), 2);
+}
+
+#[test]
+fn policy_test() {
+    let (_exporter_dir, mut exporter) = with_credentials(
+        PinInner::new(0x10, 1, 1, &Psk),
+        &[
+            ("www.example.com", "a"),
+            ("www.example.com", "b"),
+            ("login.example.org", "c"),
+        ],
+    );
+    let importer = Authenticator::new(PinInner::new(0x10, 1, 1, &Psk));
+    share_psk(&importer, &exporter);
+    let export = |exporter: &Authenticator<PinInner>, rp_id: &str| {
+        let export_request = importer
+            .construct_export_request(rp_id.to_string())
+            .expect("Construct Error,Test Failed");
+        let response = exporter.handle_request(export_request)?;
+        importer.handle_response(response)
+    };
+    let refused = |result: Result<_, AuthError>, rule: &str| match result {
+        Err(AuthError::RequestNotAllowed(message)) => {
+            assert!(message.contains(rule), "{}", message)
+        }
+        other => panic!(
+            "策略{}没有拒绝请求：{:?}",
+            rule,
+            other.map(|_: ImportResult| ())
+        ),
+    };
+    // 默认策略不做限制
+    assert_eq!(
+        export(&exporter, "www.example.com")
+            .unwrap()
+            .header
+            .items()
+            .count(),
+        2
+    );
+
+    exporter.policy.deny_rps = vec!["*.example.org".to_string()];
+    refused(export(&exporter, "login.example.org"), "deny_rps");
+    exporter.policy.allow_rps = vec![
+        "WWW.EXAMPLE.COM".to_string(),
+        "login.example.org".to_string(),
+    ];
+    refused(export(&exporter, "login.example.org"), "deny_rps");
+    exporter.policy.deny_rps.clear();
+    assert!(export(&exporter, "login.example.org").is_ok());
+    exporter.policy.allow_rps = vec!["login.example.org".to_string()];
+    refused(export(&exporter, "www.example.com"), "allow_rps");
+    exporter.policy.allow_rps.clear();
+
+    // Psk和Auth模式互不满足对方的要求
+    exporter.policy.require_auth = true;
+    refused(export(&exporter, "www.example.com"), "require_auth");
+    exporter.policy.require_auth = false;
+    exporter.policy.require_psk = true;
+    assert!(export(&exporter, "www.example.com").is_ok());
+    let auth_importer = Authenticator::new(PinInner::new(0x10, 1, 1, &Auth));
+    let offered = auth_importer.inner.support_algorithms().0;
+    exporter.inner.algorithms.extend(offered);
+    let auth_export = |exporter: &Authenticator<PinInner>| {
+        let export_request = request_for(&auth_importer);
+        let response = exporter.handle_request(export_request)?;
+        auth_importer.handle_response(response)
+    };
+    refused(auth_export(&exporter), "require_psk");
+    exporter.policy.require_psk = false;
+    exporter.policy.require_auth = true;
+    assert!(auth_export(&exporter).is_ok());
+    exporter.policy.require_auth = false;
+    exporter.policy.require_psk = true;
+
+    exporter.policy.required_suites = vec![Suite {
+        kem: 0x10,
+        kdf: 1,
+        aead: 2,
+    }];
+    refused(export(&exporter, "www.example.com"), "required_suites");
+    exporter.policy.required_suites.push(Suite {
+        kem: 0x10,
+        kdf: 1,
+        aead: 1,
+    });
+    assert!(export(&exporter, "www.example.com").is_ok());
+
+    exporter.policy.deny_importers = vec!["shared-psk".to_string()];
+    refused(export(&exporter, "www.example.com"), "deny_importers");
+    exporter.policy.deny_importers.clear();
+    exporter.policy.allow_importers = vec!["other-device".to_string()];
+    refused(export(&exporter, "www.example.com"), "allow_importers");
+    exporter.policy.allow_importers = vec!["shared-psk".to_string()];
+    assert!(export(&exporter, "www.example.com").is_ok());
+
+    exporter.policy.max_credentials = Some(1);
+    refused(export(&exporter, "www.example.com"), "max_credentials");
+    assert!(export(&exporter, "login.example.org").is_ok());
+
+    // 从文件加载策略，未知字段视为配置错误
+    let policy_dir = tempfile::tempdir().unwrap();
+    let path = policy_dir.path().join("policy.json");
+    assert!(Policy::load(&path).unwrap().deny_rps.is_empty());
+    let policy = json!({
+        "deny_rps": ["www.example.com"],
+        "require_psk": true,
+        "require_auth": true,
+        "required_suites": [{"kem": 0x10, "kdf": 1, "aead": 1}],
+        "max_credentials": 5
+    });
+    std::fs::write(&path, policy.to_string()).unwrap();
+    exporter.policy = Policy::load(&path).unwrap();
+    assert!(exporter.policy.require_psk && exporter.policy.require_auth);
+    refused(export(&exporter, "www.example.com"), "deny_rps");
+    std::fs::write(&path, json!({"deny_rp": []}).to_string()).unwrap();
+    assert!(Policy::load(&path).is_err());
+}
+
+#[test]
+fn audit_test() {
+    let (_exporter_dir, mut exporter) = exporter_with(&[("www.example.com", "audited")]);
+    let importer = Authenticator::new(PinInner::default());
+    let export = |exporter: &Authenticator<PinInner>| {
+        let export_request = request_for(&importer);
+        let response = exporter.handle_request(export_request)?;
+        importer.handle_response(response)
+    };
+    // 生成密钥时写入的记录
+    let events = |a: &Authenticator<PinInner>| -> Vec<AuditEvent> {
+        a.inner
+            .audit_log()
+            .entries()
+            .iter()
+            .map(|entry| entry.record.event)
+            .collect()
+    };
+    assert_eq!(events(&exporter), [AuditEvent::KeyGenerated; 3]);
+
+    assert!(export(&exporter).is_ok());
+    let log = exporter.inner.audit_log();
+    log.verify().expect("Verify Error,Test Failed");
+    // 第一次Auth模式导出时生成身份密钥
+    assert_eq!(
+        log.entries()[3].record.event,
+        AuditEvent::IdentityKeyGenerated
+    );
+    let exported = &log.entries()[4].record;
+    assert_eq!(exported.event, AuditEvent::Export);
+    assert_eq!(exported.outcome, AuditOutcome::Success);
+    assert_eq!(exported.rp_ids, ["www.example.com"]);
+    let suite = exported.suite.clone().unwrap();
+    // 导入方使用临时密钥，记录的是临时公钥的指纹
+    let static_key = key_fingerprint(&importer.inner.key_pair(suite.kem).unwrap().1);
+    assert!(exported
+        .peer
+        .as_ref()
+        .is_some_and(|peer| *peer != static_key));
+    let imported = importer.inner.audit_log().entries()[3].record.clone();
+    assert_eq!(imported.event, AuditEvent::Import);
+    assert_eq!(imported.suite, Some(suite.clone()));
+    assert_eq!(
+        imported.peer,
+        Some(key_fingerprint(
+            &exporter.inner.identity_key_pair(suite.kem).unwrap().1
+        ))
+    );
+
+    // 策略拒绝和其他失败同样被记录
+    exporter.policy.deny_rps = vec!["www.example.com".to_string()];
+    assert!(export(&exporter).is_err());
+    exporter.policy.deny_rps.clear();
+    assert!(importer.handle_response("{}".to_string()).is_err());
+    let refused = exporter.inner.audit_log().entries()[5].record.clone();
+    assert_eq!(refused.event, AuditEvent::PolicyRefused);
+    assert!(matches!(refused.outcome, AuditOutcome::Failure(e) if e.contains("deny_rps")));
+    // 无法解析或版本不支持的请求在处理前被拒绝，也记录为失败的导出
+    let entries = exporter.inner.audit_log().entries().len();
+    for rejected in ["not json", r#"{"version":99}"#] {
+        assert!(exporter.handle_request(rejected.to_string()).is_err());
+    }
+    let log = exporter.inner.audit_log();
+    log.verify().expect("Verify Error,Test Failed");
+    assert_eq!(log.entries().len(), entries + 2);
+    for entry in &log.entries()[entries..] {
+        assert_eq!(entry.record.event, AuditEvent::Export);
+        assert!(matches!(entry.record.outcome, AuditOutcome::Failure(_)));
+    }
+    let log = importer.inner.audit_log();
+    log.verify().expect("Verify Error,Test Failed");
+    assert_eq!(log.entries().len(), 5);
+    assert!(matches!(
+        log.entries()[4].record.outcome,
+        AuditOutcome::Failure(_)
+    ));
+
+    // 修改或删除任何一条记录都会使校验失败
+    let log = exporter.inner.audit_log();
+    let mut value = serde_json::to_value(&log).unwrap();
+    value[3]["rp_ids"] = json!(["evil.example.com"]);
+    let tampered: AuditLog = serde_json::from_value(value).unwrap();
+    assert!(matches!(
+        tampered.verify(),
+        Err(AuthError::AuditChainBroken(3))
+    ));
+    let mut value = serde_json::to_value(&log).unwrap();
+    value.as_array_mut().unwrap().remove(1);
+    let truncated: AuditLog = serde_json::from_value(value).unwrap();
+    assert!(matches!(
+        truncated.verify(),
+        Err(AuthError::AuditChainBroken(1))
+    ));
+
+    // 审计日志随密钥库持久化，重新打开时不会重复生成密钥
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("keystore.json");
+    PinInner::open(&path).expect("Open Keystore Error,Test Failed");
+    let reopened =
+        Authenticator::new(PinInner::open(&path).expect("Open Keystore Error,Test Failed"));
+    assert_eq!(events(&reopened), [AuditEvent::KeyGenerated; 3]);
+    reopened.inner.audit_log().verify().unwrap();
 }
 
 #[allow(unused)]
@@ -94,12 +1708,9 @@ fn time_test() {
             let mut kem_sum_time = Duration::new(0, 0);
             kdf_id.into_iter().for_each(|kdf| {
                 aead_id.into_iter().for_each(|aead| {
-                    let importer = Authenticator {
-                        inner: PinInner::new(kem, kdf, aead, &mode),
-                    };
-                    let exporter = Authenticator {
-                        inner: PinInner::new(kem, kdf, aead, &mode),
-                    };
+                    let importer = Authenticator::new(PinInner::new(kem, kdf, aead, &mode));
+                    let exporter = Authenticator::new(PinInner::new(kem, kdf, aead, &mode));
+                    share_psk(&importer, &exporter);
 
                     // let _ = test::gen_random_credential("www.example.com");
 